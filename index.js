@@ -1,3 +1,5 @@
+import { Input, renderMappingEditor } from './input.js';
+
 const fileReader = file => {
   const reader = new FileReader();
 
@@ -15,29 +17,93 @@ const fileReader = file => {
   });
 };
 
+// バッテリーバックアップのセーブデータはROMのファイル名ごとにlocalStorageへ保存する
+const SAVE_INTERVAL_MS = 5000;
+let saveKey = null;
+
+// NTSCのフレームレート 60.0988Hz
+const FRAME_DURATION_MS = 1000 / 60.0988;
+const MAX_FRAMES_PER_CALLBACK = 4;
+const REWIND_FRAMES = 300;
+
+const toBase64 = u8array => btoa(String.fromCharCode(...u8array));
+const fromBase64 = str => Uint8Array.from(atob(str), c => c.charCodeAt(0));
+
+const restoreSaveData = emulator => {
+  const saved = localStorage.getItem(saveKey);
+  if(saved) emulator.load_save_data(fromBase64(saved));
+};
+
+const storeSaveData = emulator => {
+  if(!saveKey) return;
+  const data = emulator.save_data();
+  if(data.length > 0) localStorage.setItem(saveKey, toBase64(data));
+};
+
+// 画面のリフレッシュレートに関係なく60.0988Hzでフレームを進める
+const startLoop = (emulator, input, ctx) => {
+  let lastTimestamp = null;
+  let elapsedMs = 0;
+  const tick = timestamp => {
+    elapsedMs += timestamp - (lastTimestamp === null ? timestamp : lastTimestamp);
+    lastTimestamp = timestamp;
+    let frames = 0;
+    while(elapsedMs >= FRAME_DURATION_MS) {
+      elapsedMs -= FRAME_DURATION_MS;
+      frames++;
+    }
+    if(frames > MAX_FRAMES_PER_CALLBACK) {
+      // タブが裏にあった後などは追いつこうとせず捨てる
+      frames = MAX_FRAMES_PER_CALLBACK;
+      elapsedMs = 0;
+    }
+    const buttons = [input.poll(0), input.poll(1)];
+    if(emulator.is_loaded()) {
+      for(let i = 0; i < frames; i++) {
+        emulator.set_buttons(0, buttons[0]);
+        emulator.set_buttons(1, buttons[1]);
+        emulator.run_frame();
+      }
+      emulator.draw(ctx);
+    }
+    requestAnimationFrame(tick);
+  };
+  requestAnimationFrame(tick);
+};
+
 window.addEventListener('load', () => {
-  const f = document.getElementById('rom_select');
-  f.addEventListener('change', e => {
-    const input = event.target;
-    if(input.files.length < 1) return;
-    if(!input.files[0]) return;
-    const file = input.files[0];
-    console.log(file)
-    const reader = new FileReader();
-    fileReader(file)
-      .then(buf => {
-        const u8array = new Uint8Array(buf);
-        console.log(u8array);
-        import('./pkg/index.js').then(pkg => {
-          console.log(pkg.set_rom(u8array));
+  import('./pkg/index.js').then(pkg => {
+    const emulator = new pkg.Emulator();
+    const input = new Input();
+    renderMappingEditor(input, document.getElementById('input_mapping'));
+    const ctx = document.getElementById('canvas').getContext('2d');
+    startLoop(emulator, input, ctx);
+
+    setInterval(() => storeSaveData(emulator), SAVE_INTERVAL_MS);
+    window.addEventListener('beforeunload', () => storeSaveData(emulator));
+    const mute = document.getElementById('mute');
+    mute.addEventListener('change', () => emulator.set_muted(mute.checked));
+    const volume = document.getElementById('volume');
+    volume.addEventListener('input', () => emulator.set_volume(volume.value / 100));
+    document.getElementById('rewind').addEventListener('click', () => {
+      if(emulator.is_loaded()) emulator.rewind(REWIND_FRAMES);
+    });
+
+    const f = document.getElementById('rom_select');
+    f.addEventListener('change', event => {
+      const input = event.target;
+      if(input.files.length < 1) return;
+      if(!input.files[0]) return;
+      const file = input.files[0];
+      fileReader(file)
+        .then(buf => {
+          storeSaveData(emulator);
+          console.log(emulator.load_rom(new Uint8Array(buf)));
+          saveKey = 'save:' + file.name;
+          restoreSaveData(emulator);
+        }).catch(reason => {
+          alert(reason);
         });
-      }).catch(reason => {
-        alert(reason);
-      });
-  });
+    });
+  }).catch(console.error);
 });
-
-// For more comments about what's going on here, check out the `hello_world`
-// example.
-import('./pkg')
-  .catch(console.error);
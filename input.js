@@ -0,0 +1,162 @@
+// キーボードとGamepad APIの入力をコントローラのボタン状態に変換する
+// ボタンのビットはsrc/sys/controller.rsのBUTTON_*と同じ
+export const BUTTONS = {
+  A: 0x01,
+  B: 0x02,
+  SELECT: 0x04,
+  START: 0x08,
+  UP: 0x10,
+  DOWN: 0x20,
+  LEFT: 0x40,
+  RIGHT: 0x80
+};
+
+const STORAGE_KEY = 'inputMapping';
+const AXIS_THRESHOLD = 0.5;
+
+// キーはKeyboardEvent.code、ゲームパッドはStandard Gamepadのボタン番号
+const DEFAULT_MAPPING = {
+  keyboard: [
+    {A: 'KeyX', B: 'KeyZ', SELECT: 'ShiftRight', START: 'Enter', UP: 'ArrowUp', DOWN: 'ArrowDown', LEFT: 'ArrowLeft', RIGHT: 'ArrowRight'},
+    {A: 'KeyH', B: 'KeyG', SELECT: 'KeyT', START: 'KeyY', UP: 'KeyW', DOWN: 'KeyS', LEFT: 'KeyA', RIGHT: 'KeyD'}
+  ],
+  gamepad: [
+    {A: 1, B: 0, SELECT: 8, START: 9, UP: 12, DOWN: 13, LEFT: 14, RIGHT: 15},
+    {A: 1, B: 0, SELECT: 8, START: 9, UP: 12, DOWN: 13, LEFT: 14, RIGHT: 15}
+  ]
+};
+
+const loadMapping = () => {
+  const mapping = JSON.parse(JSON.stringify(DEFAULT_MAPPING));
+  try {
+    const saved = JSON.parse(localStorage.getItem(STORAGE_KEY));
+    if(saved) {
+      // 保存後にボタンが増えても既定値で補う
+      for(const device of ['keyboard', 'gamepad']) {
+        for(const port of [0, 1]) {
+          Object.assign(mapping[device][port], saved[device] && saved[device][port]);
+        }
+      }
+    }
+  } catch(e) {
+    console.error(e);
+  }
+  return mapping;
+};
+
+export class Input {
+  constructor() {
+    this.mapping = loadMapping();
+    this.pressedKeys = new Set();
+    this.listening = null; // 割り当て変更待ちの {port, button, callback}
+    this.previousPadButtons = [new Set(), new Set()];
+
+    window.addEventListener('keydown', e => {
+      if(this.listening) {
+        e.preventDefault();
+        this.finishListening('keyboard', e.code);
+        return;
+      }
+      if(this.isMappedKey(e.code)) e.preventDefault();
+      this.pressedKeys.add(e.code);
+    });
+    window.addEventListener('keyup', e => {
+      this.pressedKeys.delete(e.code);
+    });
+    window.addEventListener('blur', () => this.pressedKeys.clear());
+  }
+
+  isMappedKey(code) {
+    return this.mapping.keyboard.some(keys => Object.values(keys).includes(code));
+  }
+
+  save() {
+    localStorage.setItem(STORAGE_KEY, JSON.stringify(this.mapping));
+  }
+
+  resetMapping() {
+    this.mapping = JSON.parse(JSON.stringify(DEFAULT_MAPPING));
+    this.save();
+  }
+
+  // 次に押されたキーかゲームパッドのボタンをportのbuttonに割り当てる
+  listen(port, button, callback) {
+    this.listening = {port, button, callback};
+  }
+
+  finishListening(device, value) {
+    const {port, button, callback} = this.listening;
+    this.listening = null;
+    this.mapping[device][port][button] = value;
+    this.save();
+    if(callback) callback(device, value);
+  }
+
+  // 毎フレーム呼び出してportのボタン状態を返す
+  poll(port) {
+    let buttons = 0;
+    const keys = this.mapping.keyboard[port];
+    for(const [name, bit] of Object.entries(BUTTONS)) {
+      if(this.pressedKeys.has(keys[name])) buttons |= bit;
+    }
+    return buttons | this.pollGamepad(port);
+  }
+
+  pollGamepad(port) {
+    const pads = navigator.getGamepads ? navigator.getGamepads() : [];
+    const pad = pads[port];
+    if(!pad) return 0;
+
+    const pressed = new Set();
+    pad.buttons.forEach((b, index) => {
+      if(b.pressed) pressed.add(index);
+    });
+    if(this.listening && this.listening.port === port) {
+      const newlyPressed = [...pressed].find(index => !this.previousPadButtons[port].has(index));
+      if(newlyPressed !== undefined) this.finishListening('gamepad', newlyPressed);
+    }
+    this.previousPadButtons[port] = pressed;
+
+    let buttons = 0;
+    const padButtons = this.mapping.gamepad[port];
+    for(const [name, bit] of Object.entries(BUTTONS)) {
+      if(pressed.has(padButtons[name])) buttons |= bit;
+    }
+    // 左スティックも十字キーとして扱う
+    const [x, y] = pad.axes;
+    if(x < -AXIS_THRESHOLD) buttons |= BUTTONS.LEFT;
+    if(x > AXIS_THRESHOLD) buttons |= BUTTONS.RIGHT;
+    if(y < -AXIS_THRESHOLD) buttons |= BUTTONS.UP;
+    if(y > AXIS_THRESHOLD) buttons |= BUTTONS.DOWN;
+    return buttons;
+  }
+}
+
+// 割り当て変更用のボタンを並べる ボタンを押してからキーかゲームパッドのボタンを押すと変わる
+export const renderMappingEditor = (input, container) => {
+  container.innerHTML = '';
+  for(const port of [0, 1]) {
+    const row = document.createElement('div');
+    row.textContent = `${port + 1}P `;
+    for(const name of Object.keys(BUTTONS)) {
+      const button = document.createElement('button');
+      const label = () => {
+        button.textContent = `${name}: ${input.mapping.keyboard[port][name]} / pad ${input.mapping.gamepad[port][name]}`;
+      };
+      label();
+      button.addEventListener('click', () => {
+        button.textContent = `${name}: ...`;
+        input.listen(port, name, label);
+      });
+      row.appendChild(button);
+    }
+    container.appendChild(row);
+  }
+  const reset = document.createElement('button');
+  reset.textContent = 'reset mapping';
+  reset.addEventListener('click', () => {
+    input.resetMapping();
+    renderMappingEditor(input, container);
+  });
+  container.appendChild(reset);
+};
@@ -0,0 +1,61 @@
+use rust_nes::sys::controller::{BUTTON_A, BUTTON_B, BUTTON_DOWN, BUTTON_LEFT, BUTTON_RIGHT, BUTTON_SELECT, BUTTON_START, BUTTON_UP};
+
+// 指定したフレームからボタンの押下状態を切り替える入力スクリプト
+// 1行に「フレーム ポート ボタン」を書く ボタンはA+STARTのように+でつなぎ、何も押さない場合は-
+// ポートは0が1P、1が2P #以降はコメント
+//
+//   60 0 START
+//   62 0 -
+//   120 0 A+RIGHT
+pub struct InputEvent {
+    pub frame: u64,
+    pub port: usize,
+    pub buttons: u8
+}
+
+pub fn parse(text: &str) -> Result<Vec<InputEvent>, String> {
+    let mut events = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: &str| format!("line {}: {}", index + 1, message);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(error("expected `<frame> <port> <buttons>`"));
+        }
+        let frame = fields[0].parse().map_err(|_| error("invalid frame number"))?;
+        let port = match fields[1] {
+            "0" => 0,
+            "1" => 1,
+            _ => return Err(error("port must be 0 or 1"))
+        };
+        let buttons = parse_buttons(fields[2]).ok_or_else(|| error("unknown button name"))?;
+        events.push(InputEvent{frame, port, buttons});
+    }
+    // 同じフレームの指定は書いた順に適用する
+    events.sort_by_key(|e| e.frame);
+    Ok(events)
+}
+
+fn parse_buttons(text: &str) -> Option<u8> {
+    if text == "-" {
+        return Some(0);
+    }
+    let mut buttons = 0;
+    for name in text.split('+') {
+        buttons |= match name.to_ascii_uppercase().as_str() {
+            "A" => BUTTON_A,
+            "B" => BUTTON_B,
+            "SELECT" => BUTTON_SELECT,
+            "START" => BUTTON_START,
+            "UP" => BUTTON_UP,
+            "DOWN" => BUTTON_DOWN,
+            "LEFT" => BUTTON_LEFT,
+            "RIGHT" => BUTTON_RIGHT,
+            _ => return None
+        };
+    }
+    Some(buttons)
+}
@@ -0,0 +1,104 @@
+use std::{env, fs, fs::File, io::{self, BufWriter, Write}, process};
+
+use rust_nes::sys::{audio::DEFAULT_SAMPLE_RATE, rom, system::Nes};
+
+mod input_script;
+mod png;
+mod wav;
+
+const USAGE: &str = "usage: nes-headless <rom.nes> [--frames N] [--input script.txt] [--png out.png] [--wav out.wav] [--sample-rate HZ]";
+const DEFAULT_FRAMES: u64 = 60;
+
+struct Options {
+    rom_path: String,
+    frames: u64,
+    input_path: Option<String>,
+    png_path: Option<String>,
+    wav_path: Option<String>,
+    sample_rate: u32
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut rom_path = None;
+    let mut options = Options{
+        rom_path: String::new(),
+        frames: DEFAULT_FRAMES,
+        input_path: None,
+        png_path: None,
+        wav_path: None,
+        sample_rate: DEFAULT_SAMPLE_RATE
+    };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let mut value = || iter.next().cloned().ok_or_else(|| format!("{} requires a value", arg));
+        match arg.as_str() {
+            "--frames" => options.frames = value()?.parse().map_err(|_| "invalid --frames".to_string())?,
+            "--input" => options.input_path = Some(value()?),
+            "--png" => options.png_path = Some(value()?),
+            "--wav" => options.wav_path = Some(value()?),
+            "--sample-rate" => options.sample_rate = value()?.parse().map_err(|_| "invalid --sample-rate".to_string())?,
+            _ if arg.starts_with("--") => return Err(format!("unknown option {}", arg)),
+            _ if rom_path.is_none() => rom_path = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument {}", arg))
+        }
+    }
+    options.rom_path = rom_path.ok_or("no ROM file given")?;
+    Ok(options)
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let buf = fs::read(&options.rom_path).map_err(|e| format!("{}: {}", options.rom_path, e))?;
+    let cartridge = rom::from_array(&buf).map_err(|e| format!("{}: {}", options.rom_path, e))?;
+    let events = match &options.input_path {
+        Some(path) => {
+            let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+            input_script::parse(&text).map_err(|e| format!("{}: {}", path, e))?
+        },
+        None => Vec::new()
+    };
+
+    let mut system = Nes::new(cartridge);
+    system.set_sample_rate(options.sample_rate);
+    system.reset();
+
+    let mut samples = Vec::new();
+    let mut next_event = events.iter().peekable();
+    for frame in 0..options.frames {
+        while let Some(event) = next_event.next_if(|e| e.frame <= frame) {
+            system.set_buttons(event.port, event.buttons);
+        }
+        system.run_frame();
+        samples.extend(system.drain_samples());
+    }
+
+    if let Some(path) = &options.png_path {
+        write_file(path, |out| png::write_png(out, 256, 240, &system.frame_buffer))?;
+    }
+    if let Some(path) = &options.wav_path {
+        write_file(path, |out| wav::write_wav(out, options.sample_rate, &samples))?;
+    }
+    println!("frames {} cpu_cycles {} samples {}", options.frames, system.cycle_acc, samples.len());
+    Ok(())
+}
+
+fn write_file<F>(path: &str, write: F) -> Result<(), String>
+    where F: FnOnce(&mut BufWriter<File>) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?);
+    write(&mut out).and_then(|_| out.flush()).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return;
+    }
+    let options = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("{}\n{}", e, USAGE);
+        process::exit(2);
+    });
+    if let Err(e) = run(&options) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,92 @@
+use std::io::{self, Write};
+
+// RGBAのフレームバッファを無圧縮(deflateのstoredブロック)のRGB PNGとして書き出す
+pub fn write_png<W: Write>(out: &mut W, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    out.write_all(b"\x89PNG\r\n\x1A\n")?;
+
+    let mut header = Vec::new();
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // ビット深度8 カラータイプ2(RGB) 圧縮0 フィルタ0 インターレース無し
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &header)?;
+
+    // 各行の先頭にフィルタ種別0(None)を付ける
+    let mut raw = Vec::with_capacity(((width * 3 + 1) * height) as usize);
+    for row in rgba.chunks(width as usize * 4).take(height as usize) {
+        raw.push(0);
+        for pixel in row.chunks(4) {
+            raw.extend_from_slice(&pixel[0..3]);
+        }
+    }
+    write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let mut crc = Crc32::new();
+    crc.update(kind);
+    crc.update(data);
+    out.write_all(&crc.finish().to_be_bytes())
+}
+
+// 圧縮せずにstoredブロック(最大65535バイト)を並べたzlibストリーム
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        out.push(last as u8);
+        let length = block.len() as u16;
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a: u32 = 1;
+    let mut b: u32 = 0;
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+struct Crc32 {
+    table: [u32; 256],
+    value: u32
+}
+
+impl Crc32 {
+    fn new() -> Crc32 {
+        let mut table = [0u32; 256];
+        for (n, entry) in table.iter_mut().enumerate() {
+            let mut c = n as u32;
+            for _ in 0..8 {
+                c = if c & 1 != 0 {0xEDB8_8320 ^ (c >> 1)} else {c >> 1};
+            }
+            *entry = c;
+        }
+        Crc32{table, value: 0xFFFF_FFFF}
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        for byte in data {
+            self.value = self.table[((self.value ^ *byte as u32) & 0xFF) as usize] ^ (self.value >> 8);
+        }
+    }
+
+    fn finish(&self) -> u32 {
+        self.value ^ 0xFFFF_FFFF
+    }
+}
@@ -0,0 +1,26 @@
+use std::io::{self, Write};
+
+// モノラルの音声サンプル(-1.0から1.0)を16bit PCMのWAVとして書き出す
+pub fn write_wav<W: Write>(out: &mut W, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // チャンネル数
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?; // 1秒あたりのバイト数
+    out.write_all(&2u16.to_le_bytes())?; // ブロックサイズ
+    out.write_all(&16u16.to_le_bytes())?; // ビット数
+
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        out.write_all(&value.to_le_bytes())?;
+    }
+    Ok(())
+}
@@ -1,242 +1,958 @@
-use std::{cell::RefCell, rc::Rc};
-
-use sys::{system::{Nes}};
-use wasm_bindgen::prelude::*;
-use wasm_bindgen::Clamped;
-use wasm_bindgen::JsCast;
-use web_sys::{CanvasRenderingContext2d, ImageData, console::log_1};
-
+// エミュレータ本体はsys以下 プラットフォームに依存しない
+// ブラウザ向けのwasmのフロントエンドはwebフィーチャーで有効になる
 pub mod sys;
+#[cfg(feature = "web")]
+pub mod web;
 
-fn log(s: &String) {
-    unsafe{
-        log_1(&JsValue::from(s));
-    }
+pub fn load_cartridge(buf: &[u8]) -> Result<sys::rom::Rom, sys::rom::RomError>{
+    sys::rom::from_array(buf)
 }
 
-fn window() -> web_sys::Window {
-    web_sys::window().expect("no global `window` exists")
-}
-
-fn request_animation_frame(f: &Closure<dyn FnMut()>) {
-    window()
-        .request_animation_frame(f.as_ref().unchecked_ref())
-        .expect("should register `requestAnimationFrame` OK");
-}
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+    use std::fs::File;
+    use std::io::Read;
+    use regex::Regex;
 
-fn document() -> web_sys::Document {
-    window()
-        .document()
-        .expect("should have a document on window")
-}
+    use crate::sys::{self, controller, cpu, state::StateError, system::Nes};
+
+    // PRG 16KB/CHR 8KBのNROMイメージを作る リセットベクタは$8000、NMIベクタは$8200、IRQベクタは$8100
+    fn build_test_rom(program: &[u8]) -> Vec<u8> {
+        let mut buf = vec![0u8; 0x10 + 0x4000 + 0x2000];
+        buf[0..4].copy_from_slice(b"NES\x1A");
+        buf[4] = 1;
+        buf[5] = 1;
+        buf[0x10..0x10 + program.len()].copy_from_slice(program);
+        buf[0x10 + 0x3FFA] = 0x00;
+        buf[0x10 + 0x3FFB] = 0x82;
+        buf[0x10 + 0x3FFC] = 0x00;
+        buf[0x10 + 0x3FFD] = 0x80;
+        buf[0x10 + 0x3FFE] = 0x00;
+        buf[0x10 + 0x3FFF] = 0x81;
+        buf
+    }
 
-enum AppState{
-    UNINITIALIZED,
-    READY,
-    RUN
-}
+    #[test]
+    fn rom_header_errors_and_nes2_fields() {
+        use sys::rom::{ConsoleType, RomError, Timing};
+        let buf = build_test_rom(&[]);
+        assert_eq!(Some(RomError::InvalidMagic), sys::rom::from_array(b"NES\x00").err());
+        assert_eq!(Some(RomError::TruncatedPrgRom{expected: 0x4000, actual: 0x100}), sys::rom::from_array(&buf[0..0x110]).err());
+        assert_eq!(Some(RomError::TruncatedChrRom{expected: 0x2000, actual: 0x1000}), sys::rom::from_array(&buf[0..0x5010]).err());
+        let mut unsupported = buf.clone();
+        unsupported[6] = 0xF0;
+        assert_eq!(Some(RomError::UnsupportedMapper(15)), sys::rom::from_array(&unsupported).err());
+        let mut no_prg = buf.clone();
+        no_prg[4] = 0;
+        assert_eq!(Some(RomError::MissingPrgRom), sys::rom::from_array(&no_prg).err());
+        // NES 2.0の指数表記 2^63 * 7はusizeに収まらない 2^40はファイルより大きい
+        let mut huge = buf.clone();
+        huge[7] = 0x08;
+        huge[9] = 0x0F;
+        huge[4] = 0xFF;
+        assert_eq!(Some(RomError::RomSizeOverflow), sys::rom::from_array(&huge).err());
+        huge[4] = 40 << 2;
+        assert_eq!(Some(RomError::TruncatedPrgRom{expected: 1 << 40, actual: 0x6000}), sys::rom::from_array(&huge).err());
+
+        // NES 2.0 マッパー4 サブマッパー1 PRG-NVRAM 8KB CHR-RAM 8KB PAL
+        let mut nes2 = vec![0u8; 0x10 + 0x8000];
+        nes2[0..4].copy_from_slice(b"NES\x1A");
+        nes2[4] = 2;
+        nes2[6] = 0x43;
+        nes2[7] = 0x08;
+        nes2[8] = 0x10;
+        nes2[10] = 0x70;
+        nes2[11] = 0x07;
+        nes2[12] = 0x01;
+        let rom = sys::rom::from_array(&nes2).unwrap();
+        assert!(rom.is_nes2);
+        assert_eq!((4, 1), (rom.mapper_number, rom.submapper_number));
+        assert_eq!((0, 0x2000), (rom.prg_ram_size, rom.prg_nvram_size));
+        assert_eq!((0, 0x2000), (rom.chr_rom_size, rom.chr_ram_size));
+        assert!(rom.battery);
+        assert_eq!(ConsoleType::Nes, rom.console_type);
+        assert_eq!(Timing::Pal, rom.timing);
+    }
 
-struct StateTest{
-    system: Option<Nes>,
-    app_state: AppState
-}
+    #[test]
+    fn cpu_cycles() {
+        let program = [
+            0xA2, 0xFF,       // LDX #$FF
+            0xBD, 0x01, 0x80, // LDA $8001,X ページ跨ぎ
+            0xBD, 0x00, 0x80, // LDA $8000,X
+            0x9D, 0x00, 0x02, // STA $0200,X
+            0xF0, 0x00,       // BEQ +0 分岐成立
+            0xD0, 0x00,       // BNE +0 分岐不成立
+            0xF0, 0x80,       // BEQ -128 分岐成立・ページ跨ぎ
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        let expects = [2, 5, 4, 5, 3, 2, 4];
+        for expect in expects.iter() {
+            let cycles = sys.cpu.next_cycle(&mut sys.memory_map);
+            assert_eq!(*expect, cycles);
+        }
+        assert_eq!(0x7F91, sys.cpu.program_counter);
+    }
 
-impl StateTest {
-    pub fn new() -> StateTest {
-        StateTest{system: None, app_state: AppState::UNINITIALIZED}
+    #[test]
+    fn cpu_unofficial_opcodes() {
+        let program = [
+            0xA9, 0xF0,       // LDA #$F0
+            0x0B, 0x81,       // ANC #$81
+            0xA9, 0xFF,       // LDA #$FF
+            0x4B, 0x03,       // ALR #$03
+            0x18,             // CLC
+            0xA9, 0xFF,       // LDA #$FF
+            0x6B, 0xC0,       // ARR #$C0
+            0xA9, 0x0F,       // LDA #$0F
+            0xA2, 0xFF,       // LDX #$FF
+            0xCB, 0x05,       // AXS #$05
+            0xA2, 0x03,       // LDX #$03
+            0xA0, 0x20,       // LDY #$20
+            0x9E, 0xF0, 0x05, // SHX $05F0,Y ページ跨ぎ
+            0xBB, 0x10, 0x02, // LAS $0210,Y
+            0x02,             // KIL
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        sys.memory_map.wram[0x230] = 0xC7;
+        let step = |sys: &mut Nes, count: usize| {
+            for _ in 0..count {
+                sys.cpu.next_cycle(&mut sys.memory_map);
+            }
+        };
+        step(&mut sys, 2);
+        assert_eq!(0x80, sys.cpu.reg_a);
+        assert!(sys.cpu.get_flag_c() && sys.cpu.get_flag_n());
+        step(&mut sys, 2);
+        assert_eq!(0x01, sys.cpu.reg_a);
+        assert!(sys.cpu.get_flag_c());
+        step(&mut sys, 3);
+        assert_eq!(0x60, sys.cpu.reg_a);
+        assert!(sys.cpu.get_flag_c() && !sys.cpu.get_flag_v());
+        step(&mut sys, 3);
+        assert_eq!(0x0A, sys.cpu.reg_x);
+        assert!(sys.cpu.get_flag_c());
+        // 書き込み先の上位バイトがX AND ($05+1)に置き換わる
+        step(&mut sys, 3);
+        assert_eq!(0x02, sys.memory_map.wram[0x210]);
+        assert_eq!(0x00, sys.memory_map.wram[0x610]);
+        step(&mut sys, 1);
+        assert_eq!([0xC5, 0xC5, 0xC5], [sys.cpu.reg_a, sys.cpu.reg_x, sys.cpu.reg_s]);
+
+        // KILで停止した後はNMIも受け付けずPCが変わらない
+        sys.execute();
+        assert!(sys.cpu.halted);
+        let cycle = sys.cycle_acc;
+        sys.cpu.nmi_pending = true;
+        sys.execute();
+        assert_eq!(0x801D, sys.cpu.program_counter);
+        assert_eq!(cycle + 1, sys.cycle_acc);
+        sys.reset();
+        assert!(!sys.cpu.halted);
+        assert_eq!(0x8000, sys.cpu.program_counter);
     }
 
-    pub fn set_system(&mut self, sys: Nes){
-        self.system = Some(sys);
+    #[test]
+    fn oam_dma_stalls_cpu() {
+        let program = [
+            0xA9, 0x02,       // LDA #$02
+            0x8D, 0x14, 0x40, // STA $4014
+            0xEA,             // NOP
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        sys.execute();
+        let dot_position = |ppu: &sys::ppu::Ppu| ppu.current_line as u64 * 341 + ppu.current_dot as u64;
+        let start_cycle = sys.cycle_acc;
+        let start_dot = dot_position(&sys.memory_map.ppu);
+        sys.execute();
+        // STAの4サイクルに続けて513サイクル 奇数サイクルから始まれば1サイクル多い
+        let expect = 4 + 513 + (start_cycle + 4) % 2;
+        assert_eq!(expect, sys.cycle_acc - start_cycle);
+        // 停止している間もPPUは進む
+        assert_eq!(expect * 3, (dot_position(&sys.memory_map.ppu) + 262 * 341 - start_dot) % (262 * 341));
+        sys.execute();
+        assert_eq!(expect + 2, sys.cycle_acc - start_cycle);
     }
 
-    pub fn set_state(&mut self, value: AppState){
-        self.app_state = value;
+    #[test]
+    fn cpu_brk() {
+        let program = [
+            0x00, 0xEA, // BRK
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        sys.cpu.reg_p = 0x20;
+        assert_eq!(7, sys.cpu.next_cycle(&mut sys.memory_map));
+        assert_eq!(0x8100, sys.cpu.program_counter);
+        assert_eq!(0xFA, sys.cpu.reg_s);
+        assert_eq!(0x80, sys.memory_map.wram[0x1FD]);
+        assert_eq!(0x02, sys.memory_map.wram[0x1FC]);
+        assert_eq!(0x30, sys.memory_map.wram[0x1FB]);
+        assert!(sys.cpu.get_flag_i());
     }
-}
 
-static mut testtest: Option<StateTest> = None;
+    #[test]
+    fn cpu_irq_after_cli() {
+        let program = [
+            0x58, // CLI
+            0xEA, // NOP
+            0xEA, // NOP
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        sys.cpu.set_irq(cpu::IRQ_MAPPER, true);
+        sys.cpu.next_cycle(&mut sys.memory_map);
+        // CLIの直後の1命令は割り込まれない
+        sys.cpu.next_cycle(&mut sys.memory_map);
+        assert_eq!(0x8002, sys.cpu.program_counter);
+        assert_eq!(7, sys.cpu.next_cycle(&mut sys.memory_map));
+        assert_eq!(0x8100, sys.cpu.program_counter);
+        assert_eq!(0x20, sys.memory_map.wram[0x1FB] & 0x30);
+    }
 
-#[wasm_bindgen(start)]
-pub fn run() -> Result<(), JsValue>  {
-    unsafe{
-        testtest = Some(StateTest::new());
+    #[test]
+    fn uxrom_prg_smaller_than_bank() {
+        // NES 2.0の指数表記でPRG 8KB(2^13 * 1) マッパー2
+        let mut buf = vec![0u8; 0x10 + 0x2000 + 0x2000];
+        buf[0..4].copy_from_slice(b"NES\x1A");
+        buf[4] = 13 << 2;
+        buf[5] = 1;
+        buf[6] = 0x20;
+        buf[7] = 0x08;
+        buf[9] = 0x0F;
+        buf[0x10 + 0x1FFC] = 0x34;
+        buf[0x10 + 0x1FFD] = 0x92;
+        let mut sys = Nes::new(sys::rom::from_array(&buf).unwrap());
+        sys.reset();
+        assert_eq!(0x9234, sys.cpu.program_counter);
     }
-    let f = Rc::new(RefCell::new(None));
-    let g = f.clone();
-
-    let mut i = 0;
-    *g.borrow_mut() = Some(Closure::wrap(Box::new(move || {
-        if i > 30000 {
-
-            // Drop our handle to this closure so that it will get cleaned
-            // up once we return.
-            let _ = f.borrow_mut().take();
-            return;
-        }
-
-        // Set the body's text content to how many times this
-        // requestAnimationFrame callback has fired.
-        i += 1;
-        let text = format!("requestAnimationFrame has been called {} times.", i);
-
-        let document = web_sys::window().unwrap().document().unwrap();
-        let canvas = document.get_element_by_id("canvas").unwrap();
-        let canvas: web_sys::HtmlCanvasElement = canvas
-            .dyn_into::<web_sys::HtmlCanvasElement>()
-            .map_err(|_| ())
-            .unwrap();
-    
-        let context = canvas
-            .get_context("2d")
-            .unwrap()
-            .unwrap()
-            .dyn_into::<web_sys::CanvasRenderingContext2d>()
-            .unwrap();
-    
-        draw(&context, i);
-
-        // Schedule ourself for another requestAnimationFrame callback.
-        request_animation_frame(f.borrow().as_ref().unwrap());
-    }) as Box<dyn FnMut()>));
-
-    request_animation_frame(g.borrow().as_ref().unwrap());
-    Ok(())
-}
 
-#[wasm_bindgen]
-pub fn draw(ctx: &CanvasRenderingContext2d, step: i32) -> Result<(), JsValue> {
-    // let width = 256;
-    // let height = 240;
-    // let mut data = get_image_data_demo();
-    // let data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(&mut data), width as u32, height as u32)?;
-    // ctx.put_image_data(&data, 0.0, 0.0);
-    ctx.set_fill_style(&JsValue::from("#000000"));
-    // let mut a:u8 = 0x27;
-    // a |=  1 << (0x01 as u8);
-    // log(&format!("{}", a));
-    unsafe {
-        let test = & testtest;
-
-        &mut testtest.as_mut().map(|state|{
-            let sys = &mut state.system;
-            match state.app_state {
-                AppState::READY => {
-                    (*sys).as_mut().map(|sys|{
-                        sys.reset();
-                    });
-                    state.set_state(AppState::RUN)
-                },
-                AppState::RUN => {
-                    (*sys).as_mut().map(|sys|{
-                        for i in 0..10{ // TODO: タイミング・サイクル数換算は後で実装
-                            sys.execute();
-                        }
-                        log(&format!("{:04x} {:02x} {:02x} {:02x} {:02x}", sys.cpu.program_counter, sys.cpu.reg_a, sys.cpu.reg_x, sys.cpu.reg_y, sys.cpu.reg_p));
-                    });
-                },
-                _ => {}
-            };
-        });
-
-        let mut buf_tmp = (*test).as_ref().map(|state|{
-            let sys = &state.system;
-            let buf_ = (*sys).as_ref().map(|sys|{
-                let buf = sys.frame_buffer.to_vec();
-                buf
-            });
-            match buf_ {
-                Some(b) => b,
-                None => get_image_data_demo().to_vec()
+    #[test]
+    fn mmc1_serial_prg_bank_switch() {
+        // PRG 128KB 各16KBバンクの先頭にバンク番号を書いておく
+        let mut buf = vec![0u8; 0x10 + 0x20000 + 0x2000];
+        buf[0..4].copy_from_slice(b"NES\x1A");
+        buf[4] = 8;
+        buf[5] = 1;
+        buf[6] = 0x10;
+        for bank in 0..8 {
+            buf[0x10 + bank * 0x4000] = bank as u8;
+        }
+        let rom = sys::rom::from_array(&buf).unwrap();
+        let mut sys = Nes::new(rom);
+        let mem = &mut sys.memory_map;
+        let write_serial = |mem: &mut sys::memory_map::MemoryMap, address: u32, value: u8| {
+            for i in 0..5 {
+                mem.set_from_address(address, (value >> i) & 0x01);
             }
-        });
-        let mut buf = match buf_tmp {
-            Some(b) => b,
-            None => get_image_data_demo().to_vec()
         };
-        let width = 256;
-        let height = 240;
-        let data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(&mut buf), width as u32, height as u32)?;
-        // log(&format!("{:?}", buf));
-        ctx.put_image_data(&data, 0.0, 0.0);
-
-        let size = (*test).as_ref().map(|state|{
-            let sys = &state.system;
-            let size_ = (*sys).as_ref().map(|sys|{
-                sys.memory_map.rom.prg_rom.len()
-            });
-            match size_ {
-                Some(s) => s,
-                None => 0
+        // 起動時は$C000が最終バンク固定
+        assert_eq!(7, mem.get_from_address(0xC000));
+        write_serial(mem, 0xE000, 3);
+        assert_eq!(3, mem.get_from_address(0x8000));
+        assert_eq!(7, mem.get_from_address(0xC000));
+        // 32KBモード
+        write_serial(mem, 0x8000, 0x00);
+        write_serial(mem, 0xE000, 5);
+        assert_eq!(4, mem.get_from_address(0x8000));
+        assert_eq!(5, mem.get_from_address(0xC000));
+        // bit7の書き込みでリセット
+        mem.set_from_address(0x8000, 0x01);
+        mem.set_from_address(0x8000, 0x80);
+        assert_eq!(7, mem.get_from_address(0xC000));
+    }
+
+    #[test]
+    fn mmc3_prg_bank_and_scanline_irq() {
+        // PRG 32KB 各8KBバンクの先頭にバンク番号を書いておく
+        let mut buf = vec![0u8; 0x10 + 0x8000 + 0x2000];
+        buf[0..4].copy_from_slice(b"NES\x1A");
+        buf[4] = 2;
+        buf[5] = 1;
+        buf[6] = 0x40;
+        for bank in 0..4 {
+            buf[0x10 + bank * 0x2000] = bank as u8;
+        }
+        let rom = sys::rom::from_array(&buf).unwrap();
+        let mut sys = Nes::new(rom);
+        let mem = &mut sys.memory_map;
+        mem.set_from_address(0x8000, 0x06);
+        mem.set_from_address(0x8001, 1);
+        assert_eq!(1, mem.get_from_address(0x8000));
+        assert_eq!(2, mem.get_from_address(0xC000));
+        assert_eq!(3, mem.get_from_address(0xE000));
+        // PRGバンクモード1で$8000と$C000が入れ替わる
+        mem.set_from_address(0x8000, 0x46);
+        assert_eq!(2, mem.get_from_address(0x8000));
+        assert_eq!(1, mem.get_from_address(0xC000));
+
+        // BGは$0000、スプライトは$1000のパターンテーブル
+        mem.set_from_address(0x2000, 0x08);
+        mem.set_from_address(0x2001, 0x18);
+        mem.set_from_address(0xC000, 2);
+        mem.set_from_address(0xC001, 0);
+        mem.set_from_address(0xE001, 0);
+        // プリレンダーラインで再読み込み、ライン0で1、ライン1で0になりIRQ
+        let mut dots = 0;
+        while !mem.rom.mapper.irq() {
+            mem.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+            dots += 1;
+            if dots % 3 == 0 {
+                mem.rom.mapper.cpu_clock();
             }
-        });
-        let size = match size {
-            Some(s) => s,
-            None => 0
-        };
-        ctx.put_image_data(&ImageData::new_with_u8_clamped_array_and_sh(Clamped(&mut [0; 50*10*4]), 50, 10)?, 10.0, 240.0);
-        ctx.fill_text(&size.to_string(), 40.0, 248.0);
+            assert!(dots < 341 * 262);
+        }
+        assert_eq!(1, mem.ppu.current_line);
+        // $E000で解除
+        mem.set_from_address(0xE000, 0);
+        assert!(!mem.rom.mapper.irq());
     }
-    ctx.fill_text(&step.to_string(), 10.0, 248.0)
-}
 
-#[wasm_bindgen]
-pub fn set_rom(buf: &mut [u8]) -> String {
-    let rom = load_cartridge(buf);
-    let str = format!("prg_rom {}bytes\nchr_rom {}bytes\n", rom.prg_rom.len(), rom.chr_rom.len());
-
-    unsafe{
-        let mut option = &mut testtest;
-        let mut test1 = (*option).as_mut().unwrap();
-        test1.set_state(AppState::UNINITIALIZED);
-        test1.set_system(Nes::new(rom));
-        test1.set_state(AppState::READY);
+    #[test]
+    fn chr_ram_written_through_ppu_data() {
+        // CHR ROM 0バンクのUxROM
+        let mut buf = build_test_rom(&[]);
+        buf[5] = 0;
+        buf[6] = 0x20;
+        buf.truncate(0x10 + 0x4000);
+        let rom = sys::rom::from_array(&buf).unwrap();
+        assert_eq!(0x2000, rom.chr_ram_size);
+        let mut sys = Nes::new(rom);
+        let mem = &mut sys.memory_map;
+        mem.set_from_address(0x2006, 0x1F);
+        mem.set_from_address(0x2006, 0xF0);
+        mem.set_from_address(0x2007, 0xA5);
+        assert_eq!(0xA5, mem.rom.mapper.ppu_read(0x1FF0));
+        mem.set_from_address(0x2006, 0x1F);
+        mem.set_from_address(0x2006, 0xF0);
+        mem.get_from_address(0x2007);
+        assert_eq!(0xA5, mem.get_from_address(0x2007));
     }
 
-    str
-}
+    #[test]
+    fn battery_backed_prg_ram_save_and_load() {
+        let mut buf = build_test_rom(&[]);
+        let sys = Nes::new(sys::rom::from_array(&buf).unwrap());
+        assert_eq!(None, sys.save_data());
+
+        buf[6] |= 0x02;
+        let mut sys = Nes::new(sys::rom::from_array(&buf).unwrap());
+        sys.memory_map.set_from_address(0x6000, 0x12);
+        sys.memory_map.set_from_address(0x7FFF, 0x34);
+        let save = sys.save_data().unwrap();
+        assert_eq!(0x2000, save.len());
+
+        let mut sys = Nes::new(sys::rom::from_array(&buf).unwrap());
+        sys.load_save_data(&save);
+        assert_eq!(0x12, sys.memory_map.get_from_address(0x6000));
+        assert_eq!(0x34, sys.memory_map.get_from_address(0x7FFF));
+    }
 
-pub fn load_cartridge(buf: &[u8]) -> sys::rom::Rom{
-    let cartridge = sys::rom::from_array(buf);
-    cartridge
-}
+    #[test]
+    fn apu_pulse_sweep_and_length_counter() {
+        let rom = sys::rom::from_array(&build_test_rom(&[])).unwrap();
+        let mut sys = Nes::new(rom);
+        let mem = &mut sys.memory_map;
+        mem.set_from_address(0x4015, 0x03);
+        // 周期$100 スイープ有効 減算 シフト1
+        for base in [0x4000, 0x4004].iter() {
+            mem.set_from_address(base + 0, 0x1F);
+            mem.set_from_address(base + 1, 0x89);
+            mem.set_from_address(base + 2, 0x00);
+            mem.set_from_address(base + 3, 0x09); // 長さ254
+        }
+        assert_eq!(0x03, mem.get_from_address(0x4015));
+        // 矩形波1は1の補数、矩形波2は2の補数で減算される
+        assert_eq!(0x7F, mem.apu.pulse1.target_period());
+        assert_eq!(0x80, mem.apu.pulse2.target_period());
+        for _ in 0..14913 {
+            mem.apu.clock();
+        }
+        assert_eq!(253, mem.apu.pulse1.length_counter);
+        assert_eq!(0x7F, mem.apu.pulse1.timer_period);
+        assert_eq!(0x80, mem.apu.pulse2.timer_period);
+        // 無効にすると長さカウンタは0になる
+        mem.set_from_address(0x4015, 0x02);
+        assert_eq!(0x02, mem.get_from_address(0x4015));
+    }
+
+    #[test]
+    fn apu_frame_irq_and_dmc_irq() {
+        let program = [
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        // 4ステップモードでは29829サイクル目にフレームIRQ
+        while sys.cycle_acc < 29829 {
+            assert_eq!(0, sys.memory_map.get_from_address(0x4015) & 0x40);
+            sys.execute();
+        }
+        assert!(sys.cpu.is_irq_asserted());
+        assert_eq!(0x40, sys.memory_map.get_from_address(0x4015) & 0x40);
+        assert_eq!(0, sys.memory_map.get_from_address(0x4015) & 0x40);
+        // 5ステップモードではフレームIRQは発生しない
+        sys.memory_map.set_from_address(0x4017, 0x80);
+        let start = sys.cycle_acc;
+        while sys.cycle_acc < start + 37282 {
+            sys.execute();
+        }
+        assert!(!sys.memory_map.apu.frame_irq);
+
+        // $C000からの17バイトのサンプル 読み終わるとDMC IRQ
+        sys.memory_map.set_from_address(0x4010, 0x8F);
+        sys.memory_map.set_from_address(0x4012, 0x00);
+        sys.memory_map.set_from_address(0x4013, 0x01);
+        sys.memory_map.set_from_address(0x4015, 0x10);
+        assert_eq!(0x10, sys.memory_map.get_from_address(0x4015));
+        let start = sys.cycle_acc;
+        while !sys.cpu.is_irq_asserted() {
+            sys.execute();
+            assert!(sys.cycle_acc < start + 17 * 8 * 54 + 100);
+        }
+        assert_eq!(0x80, sys.memory_map.get_from_address(0x4015));
+        // $4015への書き込みでDMC IRQはクリアされる
+        sys.memory_map.set_from_address(0x4015, 0x00);
+        assert_eq!(0x00, sys.memory_map.get_from_address(0x4015));
+    }
 
-fn get_image_data_demo() -> Vec<u8> {
-    let mut data = Vec::new();
+    #[test]
+    fn audio_samples_resampled_from_cpu_clock() {
+        let program = [
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        sys.set_sample_rate(48000);
+        // 矩形波1 デューティ50% 固定音量15 約440Hz
+        sys.memory_map.set_from_address(0x4015, 0x01);
+        sys.memory_map.set_from_address(0x4000, 0xBF);
+        sys.memory_map.set_from_address(0x4002, 0xFD);
+        sys.memory_map.set_from_address(0x4003, 0x00);
+        while sys.cycle_acc < 1_789_773 / 10 {
+            sys.execute();
+        }
+        let samples = sys.drain_samples();
+        assert!((samples.len() as i32 - 4800).abs() <= 1);
+        // ハイパスフィルタを通るので正負に振れる
+        assert!(samples.iter().any(|&s| s > 0.05));
+        assert!(samples.iter().any(|&s| s < -0.05));
+        assert!(sys.drain_samples().is_empty());
+    }
 
-    for x in 0..240 {
-        for y in 0..256 {
-            if x + y < 128 {
-                data.push((255) as u8);
-                data.push((127) as u8);
-                data.push((127) as u8);
+    #[test]
+    fn audio_attenuates_above_nyquist() {
+        // 出力のナイキスト周波数(24kHz)を超える矩形波は折り返さずにほぼ消える
+        let rms = |frequency: f64| {
+            let mut audio = sys::audio::AudioOutput::new(48000);
+            let period = sys::audio::CPU_CLOCK_HZ / frequency;
+            for cycle in 0..1_789_773 / 10 {
+                let phase = (cycle as f64 / period).fract();
+                audio.push(if phase < 0.5 {0.5} else {0.0});
             }
-            else {
-                data.push((255) as u8);
-                data.push((255) as u8);
-                data.push((255) as u8);
+            // フィルタが落ち着いた後半だけを見る
+            let samples = audio.drain();
+            let tail = &samples[samples.len() / 2..];
+            (tail.iter().map(|s| s * s).sum::<f32>() / tail.len() as f32).sqrt()
+        };
+        let audible = rms(1000.0);
+        let ultrasonic = rms(30000.0);
+        assert!(audible > 0.2, "{}", audible);
+        assert!(ultrasonic < 0.01, "{}", ultrasonic);
+    }
+
+    #[test]
+    fn run_frame_and_odd_frame_dot_skip() {
+        let program = [
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        sys.run_frame();
+        // JMPの3サイクル単位で止まるので誤差が出る
+        // 最初はプリレンダーラインの先頭からVBLANKの開始(ライン241のドット1)まで
+        let first_cycles = sys.cycle_acc as i64;
+        assert!((first_cycles - (341 * 242 + 1) / 3).abs() <= 3, "{}", first_cycles);
+        assert_eq!(1, sys.memory_map.ppu.frame_count);
+        let start = sys.cycle_acc;
+        sys.run_frame();
+        let cycles = (sys.cycle_acc - start) as i64;
+        assert!((cycles - 29781).abs() <= 3);
+
+        // 描画が有効なら奇数フレームは1ドット短い
+        sys.memory_map.set_from_address(0x2001, 0x18);
+        let mut frame_dots = vec![];
+        let mut dots = 0;
+        while frame_dots.len() < 3 {
+            let frame_count = sys.memory_map.ppu.frame_count;
+            sys.memory_map.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+            dots += 1;
+            if sys.memory_map.ppu.frame_count != frame_count {
+                frame_dots.push(dots);
+                dots = 0;
             }
-            data.push(255);
         }
+        assert_eq!(341 * 262 * 2 - 1, frame_dots[1] + frame_dots[2]);
+        assert_eq!(1, (frame_dots[1] as i32 - frame_dots[2] as i32).abs());
     }
-    data
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{fs, io};
-    use std::fs::File;
-    use std::io::Read;
-    use regex::Regex;
+    #[test]
+    fn controller_shift_register() {
+        let rom = sys::rom::from_array(&build_test_rom(&[])).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.set_buttons(0, controller::BUTTON_A | controller::BUTTON_START | controller::BUTTON_RIGHT);
+        // 存在しないポートは無視される
+        sys.set_buttons(2, 0xFF);
+        sys.memory_map.set_from_address(0x4016, 1);
+        sys.memory_map.set_from_address(0x4016, 0);
+        let expects = [1, 0, 0, 1, 0, 0, 0, 1, 1, 1];
+        for expect in expects.iter() {
+            assert_eq!(0x40 | expect, sys.memory_map.get_from_address(0x4016));
+        }
+        assert_eq!(0x40, sys.memory_map.get_from_address(0x4017));
+    }
+
+    #[test]
+    fn bg_scroll_wraps_into_next_nametable() {
+        let program = [
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let mut buf = build_test_rom(&program);
+        buf[6] = 0x01; // 垂直ミラー
+        // タイル0は全ピクセル1、タイル1は全ピクセル2
+        for i in 0..8 {
+            buf[0x4010 + i] = 0xFF;
+            buf[0x4010 + 0x10 + 8 + i] = 0xFF;
+        }
+        let rom = sys::rom::from_array(&buf).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        let write = |sys: &mut Nes, address: u32, value: u8| sys.memory_map.set_from_address(address, value);
+        write(&mut sys, 0x2006, 0x24);
+        write(&mut sys, 0x2006, 0x00);
+        for _ in 0..0x3C0 {
+            write(&mut sys, 0x2007, 0x01);
+        }
+        write(&mut sys, 0x2006, 0x3F);
+        write(&mut sys, 0x2006, 0x00);
+        for value in [0x0F, 0x16, 0x2A].iter() {
+            write(&mut sys, 0x2007, *value);
+        }
+        write(&mut sys, 0x2000, 0x00);
+        write(&mut sys, 0x2005, 8);
+        write(&mut sys, 0x2005, 0);
+        write(&mut sys, 0x2001, 0x0A);
+        while sys.cycle_acc < 29781 * 2 {
+            sys.execute();
+        }
+        let pixel = |x: usize, y: usize| sys.frame_buffer[(y * 256 + x) * 4..(y * 256 + x) * 4 + 3].to_vec();
+        for y in [0, 100, 239].iter() {
+            // タイル0も背景色ではなく描画される
+            assert_ne!(vec![0, 0, 0], pixel(0, *y));
+            assert_eq!(pixel(0, *y), pixel(247, *y));
+            assert_eq!(pixel(248, *y), pixel(255, *y));
+            assert_ne!(pixel(247, *y), pixel(248, *y));
+        }
+    }
+
+    #[test]
+    fn mid_scanline_ppu_addr_write() {
+        let program = [
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let mut buf = build_test_rom(&program);
+        buf[6] = 0x01; // 垂直ミラー
+        // タイル0は全ピクセル1、タイル1は全ピクセル2
+        for i in 0..8 {
+            buf[0x4010 + i] = 0xFF;
+            buf[0x4010 + 0x10 + 8 + i] = 0xFF;
+        }
+        let rom = sys::rom::from_array(&buf).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        let write = |sys: &mut Nes, address: u32, value: u8| sys.memory_map.set_from_address(address, value);
+        write(&mut sys, 0x2006, 0x24);
+        write(&mut sys, 0x2006, 0x00);
+        for _ in 0..0x3C0 {
+            write(&mut sys, 0x2007, 0x01);
+        }
+        write(&mut sys, 0x2006, 0x3F);
+        write(&mut sys, 0x2006, 0x00);
+        for value in [0x0F, 0x16, 0x2A].iter() {
+            write(&mut sys, 0x2007, *value);
+        }
+        write(&mut sys, 0x2000, 0x00);
+        write(&mut sys, 0x2005, 0);
+        write(&mut sys, 0x2005, 0);
+        write(&mut sys, 0x2001, 0x0A);
+        while sys.cycle_acc < 29781 {
+            sys.execute();
+        }
+        while sys.memory_map.ppu.current_line != 100 || sys.memory_map.ppu.current_dot != 128 {
+            sys.memory_map.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+        }
+        // ドット128のcoarse Xのインクリメント後にvを右のネームテーブルへ切り替える
+        // ドット136で読み込むX=144からのタイルから切り替わる
+        write(&mut sys, 0x2006, 0x24);
+        write(&mut sys, 0x2006, 0x00);
+        while sys.memory_map.ppu.current_line != 101 {
+            sys.memory_map.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+        }
+        let pixel = |x: usize, y: usize| sys.frame_buffer[(y * 256 + x) * 4..(y * 256 + x) * 4 + 3].to_vec();
+        assert_ne!(pixel(0, 100), pixel(255, 100));
+        assert_eq!(pixel(0, 100), pixel(143, 100));
+        assert_eq!(pixel(144, 100), pixel(255, 100));
+        assert_eq!(pixel(0, 99), pixel(255, 99));
+    }
+
+    #[test]
+    fn sprite_zero_hit_and_overflow() {
+        let program = [
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let mut buf = build_test_rom(&program);
+        // タイル0は全ピクセル1
+        for i in 0..8 {
+            buf[0x4010 + i] = 0xFF;
+        }
+        let rom = sys::rom::from_array(&buf).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        // 画面外に退避
+        for value in sys.memory_map.ppu.ppu_oam.iter_mut() {
+            *value = 0xFF;
+        }
+        sys.memory_map.ppu.ppu_oam[0..4].copy_from_slice(&[49, 0, 0, 100]);
+        for i in 1..10 {
+            sys.memory_map.ppu.ppu_oam[i * 4..i * 4 + 4].copy_from_slice(&[150, 0, 0, 0]);
+        }
+        sys.memory_map.set_from_address(0x2001, 0x1E);
+        while sys.memory_map.ppu.current_line != 0 {
+            sys.execute();
+        }
+        while sys.memory_map.ppu.current_line != 49 {
+            sys.execute();
+        }
+        // 1ドットずつ進めて、スプライトと背景が最初に重なるX=100を描くドット101でヒットすることを確認する
+        while sys.memory_map.ppu.ppu_reg[2] & 0x40 == 0 {
+            sys.memory_map.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+        }
+        assert_eq!(50, sys.memory_map.ppu.current_line);
+        assert_eq!(101, sys.memory_map.ppu.current_dot);
+        assert_eq!(0, sys.memory_map.ppu.ppu_reg[2] & 0x20);
+        while sys.memory_map.ppu.current_line != 152 {
+            sys.execute();
+        }
+        assert_eq!(0x20, sys.memory_map.ppu.ppu_reg[2] & 0x20);
+    }
+
+    #[test]
+    fn ppu_scroll_and_addr_share_write_toggle() {
+        let rom = sys::rom::from_array(&build_test_rom(&[])).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.memory_map.set_from_address(0x2000, 0x02);
+        sys.memory_map.set_from_address(0x2005, 0x7D);
+        sys.memory_map.set_from_address(0x2005, 0x5E);
+        assert_eq!(0x696F, sys.memory_map.ppu.tmp_vram_addr);
+        assert_eq!(5, sys.memory_map.ppu.fine_x);
+
+        // $2005の1回目の直後の$2006は2回目の書き込みとして扱われる
+        sys.memory_map.set_from_address(0x2005, 0x00);
+        sys.memory_map.set_from_address(0x2006, 0xEF);
+        assert_eq!(0x69EF, sys.memory_map.ppu.vram_addr);
+
+        // $2002の読み込みでラッチがリセットされる
+        sys.memory_map.set_from_address(0x2006, 0x21);
+        sys.memory_map.get_from_address(0x2002);
+        sys.memory_map.set_from_address(0x2006, 0x23);
+        sys.memory_map.set_from_address(0x2006, 0xC0);
+        assert_eq!(0x23C0, sys.memory_map.ppu.vram_addr);
+    }
+
+    #[test]
+    fn ppu_data_read_buffer_and_palette_mirror() {
+        let rom = sys::rom::from_array(&build_test_rom(&[])).unwrap();
+        let mut sys = Nes::new(rom);
+        let mem = &mut sys.memory_map;
+        mem.set_from_address(0x2006, 0x2C);
+        mem.set_from_address(0x2006, 0x05);
+        mem.set_from_address(0x2007, 0xAB);
+        // 水平ミラーなので$2C05は$2805と同じ
+        mem.set_from_address(0x2006, 0x28);
+        mem.set_from_address(0x2006, 0x05);
+        mem.get_from_address(0x2007);
+        assert_eq!(0xAB, mem.get_from_address(0x2007));
+
+        mem.set_from_address(0x2006, 0x3F);
+        mem.set_from_address(0x2006, 0x10);
+        mem.set_from_address(0x3F07, 0x21); // $2007のミラー
+        mem.set_from_address(0x2006, 0x7F); // 上位2bitは無視されて$3F00
+        mem.set_from_address(0x2006, 0x00);
+        assert_eq!(0x21, mem.get_from_address(0x2007));
+    }
+
+    #[test]
+    fn oam_dma_and_oam_data_write() {
+        let program = [
+            0xA2, 0x00,       // LDX #$00
+            0x8A,             // TXA
+            0x9D, 0x00, 0x02, // STA $0200,X
+            0xE8,             // INX
+            0xD0, 0xF9,       // BNE -7
+            0xA9, 0x04,       // LDA #$04
+            0x8D, 0x03, 0x20, // STA $2003
+            0xA9, 0x02,       // LDA #$02
+            0x8D, 0x14, 0x40, // STA $4014
+            0x4C, 0x13, 0x80, // JMP $8013
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        while sys.cpu.program_counter != 0x8013 {
+            sys.execute();
+        }
+        // OAMADDRの4から書き込まれて末尾は先頭に回り込む
+        for i in 0..0x100 {
+            assert_eq!(i as u8, sys.memory_map.ppu.ppu_oam[(i + 4) & 0xFF]);
+        }
+
+        // WRAM以外のページからも転送できる
+        sys.memory_map.set_from_address(0x2003, 0x00);
+        sys.memory_map.set_from_address(0x4014, 0x80);
+        assert_eq!(program[..], sys.memory_map.ppu.ppu_oam[..program.len()]);
+
+        sys.memory_map.set_from_address(0x2003, 0x10);
+        sys.memory_map.set_from_address(0x2004, 0xAB);
+        sys.memory_map.set_from_address(0x2004, 0xCD);
+        assert_eq!([0xAB, 0xCD], sys.memory_map.ppu.ppu_oam[0x10..0x12]);
+        assert_eq!(0x12, sys.memory_map.ppu.ppu_reg[3]);
+    }
+
+    #[test]
+    fn nmi_hijacks_brk() {
+        let mut program = vec![0x4C, 0x00, 0x80]; // JMP $8000
+        program.resize(0x10, 0xEA);
+        program.extend_from_slice(&[0x00, 0xEA]); // $8010: BRK
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        sys.memory_map.set_from_address(0x2000, 0x80);
+        while sys.memory_map.ppu.current_line != 240 {
+            sys.execute();
+        }
+        // NMIが発生するまでのドット数を数えてから戻す
+        let state = sys.save_state();
+        let mut dots = 0;
+        while !sys.cpu.nmi_pending {
+            sys.memory_map.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+            dots += 1;
+        }
+
+        // BRKの2サイクル目(スタックへの書き込みの前)にNMIが発生する
+        sys.load_state(&state).unwrap();
+        for _ in 0..dots - 6 {
+            sys.memory_map.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+        }
+        sys.cpu.program_counter = 0x8010;
+        sys.execute();
+        assert_eq!(0x8200, sys.cpu.program_counter);
+        assert!(!sys.cpu.nmi_pending);
+        // 積まれるのはBRKのPC+2とBフラグ付きのP
+        let s = sys.cpu.reg_s as usize;
+        assert_eq!(0x30, sys.memory_map.wram[0x101 + s] & 0x30);
+        assert_eq!(0x12, sys.memory_map.wram[0x102 + s]);
+        assert_eq!(0x80, sys.memory_map.wram[0x103 + s]);
+
+        // 5サイクル目以降のNMIは乗っ取らず、BRKの後に処理される
+        sys.load_state(&state).unwrap();
+        for _ in 0..dots - 15 {
+            sys.memory_map.ppu_next_cycle(&mut sys.frame_buffer, &mut sys.cpu);
+        }
+        sys.cpu.program_counter = 0x8010;
+        sys.execute();
+        assert_eq!(0x8100, sys.cpu.program_counter);
+        assert!(sys.cpu.nmi_pending);
+    }
+
+    #[test]
+    fn nmi_enabled_by_ppuctrl() {
+        let program = [
+            0x78,             // SEI
+            0x4C, 0x01, 0x80, // JMP $8001
+        ];
+        let rom = sys::rom::from_array(&build_test_rom(&program)).unwrap();
+        let mut sys = Nes::new(rom);
+        sys.reset();
+        // $2000 bit7が0の間はVBLANKになってもNMIは発生しない
+        while sys.cycle_acc < 40000 {
+            sys.execute();
+            assert!(sys.cpu.program_counter < 0x8200);
+        }
+        // VBLANK中にNMIを有効化するとIフラグに関係なく即座にNMIが発生する
+        while sys.memory_map.ppu.ppu_reg[2] & 0x80 == 0 {
+            sys.execute();
+        }
+        sys.memory_map.set_from_address(0x2000, 0x80);
+        sys.execute();
+        sys.execute();
+        assert_eq!(0x8200, sys.cpu.program_counter);
+    }
+
+    #[test]
+    fn save_state_round_trip_and_rom_check() {
+        let program = [
+            0xE6, 0x00,       // INC $00
+            0x8D, 0x00, 0x40, // STA $4000
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let buf = build_test_rom(&program);
+        let mut sys = Nes::new(sys::rom::from_array(&buf).unwrap());
+        sys.reset();
+        sys.run_frame();
+        let state = sys.save_state();
+        sys.run_frame();
+        sys.run_frame();
+        let expected = sys.save_state();
+
+        // 読み込んだ時点から同じだけ進めれば同じ状態になる
+        sys.load_state(&state).unwrap();
+        assert_eq!(state, sys.save_state());
+        sys.run_frame();
+        sys.run_frame();
+        assert_eq!(expected, sys.save_state());
+
+        // 壊れたデータは拒否して現在の状態を保つ
+        assert_eq!(Err(StateError::Truncated), sys.load_state(&state[..state.len() - 1]));
+        assert_eq!(Err(StateError::InvalidMagic), sys.load_state(b"NES"));
+        assert_eq!(expected, sys.save_state());
+
+        // 範囲外の値を含む状態を作って読み込ませる
+        let corruptions: [fn(&mut Nes); 4] = [
+            |nes| nes.memory_map.apu.noise.timer_period = 0,
+            |nes| nes.memory_map.apu.dmc.timer_period = 0,
+            |nes| nes.memory_map.apu.dmc.bits_remaining = 0,
+            |nes| nes.cpu.pending_vector = Some(0x1234),
+        ];
+        for corrupt in corruptions.iter() {
+            let mut corrupted = Nes::new(sys::rom::from_array(&buf).unwrap());
+            corrupted.load_state(&state).unwrap();
+            corrupt(&mut corrupted);
+            assert_eq!(Err(StateError::Corrupted), sys.load_state(&corrupted.save_state()));
+            assert_eq!(expected, sys.save_state());
+        }
+
+        // 割り込みシーケンスの途中の状態も保存される
+        let mut interrupted = Nes::new(sys::rom::from_array(&buf).unwrap());
+        interrupted.load_state(&state).unwrap();
+        interrupted.cpu.pending_vector = Some(0xFFFA);
+        sys.load_state(&interrupted.save_state()).unwrap();
+        assert_eq!(Some(0xFFFA), sys.cpu.pending_vector);
+
+        let mut other_buf = buf.clone();
+        other_buf[16 + 0x10] = 0xEA;
+        let mut other = Nes::new(sys::rom::from_array(&other_buf).unwrap());
+        assert_eq!(Err(StateError::RomMismatch), other.load_state(&state));
+    }
+
+    #[test]
+    fn rewind_restores_earlier_snapshot() {
+        let program = [
+            0xE6, 0x00,       // INC $00
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let mut sys = Nes::new(sys::rom::from_array(&build_test_rom(&program)).unwrap());
+        sys.reset();
+        assert_eq!(Ok(None), sys.rewind(1));
+        sys.enable_rewind(2, usize::MAX);
+        let mut states = Vec::new();
+        for _ in 0..80 {
+            sys.run_frame();
+            states.push((sys.memory_map.ppu.frame_count, sys.save_state()));
+        }
+        let rewind = sys.rewind.as_ref().unwrap();
+        assert_eq!(40, rewind.len());
+        let usage = rewind.memory_usage();
+        assert!(usage < states[0].1.len() * 6);
+
+        // 3フレーム前の指定なら直前のスナップショットの4フレーム前に戻る
+        assert_eq!(Ok(Some(4)), sys.rewind(3));
+        assert_eq!(76, sys.memory_map.ppu.frame_count);
+        assert_eq!(states[75].1, sys.save_state());
+        assert_eq!(Ok(Some(2)), sys.rewind(1));
+        assert_eq!(states[73].1, sys.save_state());
+        // 戻った後も続けて履歴を取れる
+        sys.run_frame();
+        sys.run_frame();
+        assert_eq!(Ok(Some(2)), sys.rewind(2));
+        assert_eq!(states[73].1, sys.save_state());
+
+        // 読み込めないスナップショットならエラーを返し、状態も履歴もそのまま残す
+        let len = sys.rewind.as_ref().unwrap().len();
+        sys.rewind.as_mut().unwrap().push(74, b"broken".to_vec());
+        assert_eq!(Err(StateError::InvalidMagic), sys.rewind(0));
+        assert_eq!(states[73].1, sys.save_state());
+        assert_eq!(len + 1, sys.rewind.as_ref().unwrap().len());
+
+        // 上限を超えたら古いキーフレームの組から捨てる
+        sys.enable_rewind(1, usage);
+        for _ in 0..150 {
+            sys.run_frame();
+        }
+        let rewind = sys.rewind.as_ref().unwrap();
+        assert!(rewind.memory_usage() <= usage);
+        assert!(rewind.len() < 60);
+        assert_eq!(Ok(None), sys.rewind(150));
+    }
+
+    #[test]
+    fn rewind_budget_caps_single_keyframe_group() {
+        let program = [
+            0xE6, 0x00,       // INC $00
+            0x4C, 0x00, 0x80, // JMP $8000
+        ];
+        let mut sys = Nes::new(sys::rom::from_array(&build_test_rom(&program)).unwrap());
+        sys.reset();
+        // キーフレーム1つ分を測ってから、差分が数個しか入らない上限にする
+        sys.enable_rewind(1, usize::MAX);
+        sys.run_frame();
+        let budget = sys.rewind.as_ref().unwrap().memory_usage() + 200;
+        sys.enable_rewind(1, budget);
+        // キーフレームを取り直す間隔より短い間だけ進め、最新のキーフレームの組の中で上限を超えさせる
+        for _ in 0..20 {
+            sys.run_frame();
+            let rewind = sys.rewind.as_ref().unwrap();
+            assert!(rewind.memory_usage() <= budget, "{} > {}", rewind.memory_usage(), budget);
+        }
+        // キーフレームと直近の差分は残る
+        let rewind = sys.rewind.as_ref().unwrap();
+        assert!(rewind.len() >= 2 && rewind.len() < 20);
+        assert_eq!(Ok(Some(1)), sys.rewind(1));
+
+        // キーフレームも入らない上限では何も残さない
+        sys.enable_rewind(1, 100);
+        sys.run_frame();
+        assert_eq!(0, sys.rewind.as_ref().unwrap().memory_usage());
+        assert_eq!(Ok(None), sys.rewind(1));
+    }
 
-    use crate::sys::{self, system::Nes};
-    
     #[test]
     fn nestest() {
         let buf = fs::read("./nestest.nes").expect("Unable to read file");
         let log_file = fs::read_to_string("./nestest.log").expect("Unable to read file");
         let log_line = log_file.lines();
-        let rom = sys::rom::from_array(&buf);
+        let rom = sys::rom::from_array(&buf).unwrap();
         let mut sys = Nes::new(rom);
         sys.reset();
         sys.cpu.program_counter = 0xC000;
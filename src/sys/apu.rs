@@ -0,0 +1,880 @@
+use super::state::{StateError, StateReader, StateWriter};
+
+// $4003/$4007などの上位5bitから決まる長さカウンタの値
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30
+];
+
+// デューティ比 12.5% 25% 50% 25%(反転)
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1]
+];
+
+// 三角波の32段階の出力
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15
+];
+
+// ノイズとDMCの周期(CPUサイクル NTSC)
+const NOISE_PERIOD_TABLE: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const DMC_RATE_TABLE: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+// 非線形ミキサーの出力
+// 矩形波: 95.52 / (8128 / (pulse1 + pulse2) + 100)
+// 三角波・ノイズ・DMC: 163.67 / (24329 / (3 * triangle + 2 * noise + dmc) + 100)
+const PULSE_MIX_TABLE: [f32; 31] = generate_pulse_mix_table();
+const TND_MIX_TABLE: [f32; 203] = generate_tnd_mix_table();
+
+const fn generate_pulse_mix_table() -> [f32; 31]{
+    let mut result = [0.0; 31];
+    let mut i = 1;
+    while i < 31 {
+        result[i] = 95.52 / (8128.0 / i as f32 + 100.0);
+        i += 1;
+    }
+    result
+}
+
+const fn generate_tnd_mix_table() -> [f32; 203]{
+    let mut result = [0.0; 203];
+    let mut i = 1;
+    while i < 203 {
+        result[i] = 163.67 / (24329.0 / i as f32 + 100.0);
+        i += 1;
+    }
+    result
+}
+
+// フレームシーケンサの各ステップのCPUサイクル
+const FRAME_STEP_CYCLES: [u64; 4] = [7457, 14913, 22371, 29829];
+const FRAME_CYCLES_4_STEP: u64 = 29830;
+const FRAME_STEP5_CYCLE: u64 = 37281;
+const FRAME_CYCLES_5_STEP: u64 = 37282;
+
+// 音量エンベロープ 1/4フレームごとに減衰する
+pub struct Envelope {
+    pub start: bool,
+    pub loop_flag: bool, // 長さカウンタの停止フラグと共用
+    pub constant_volume: bool,
+    pub volume: u8, // 固定音量、またはエンベロープの分周期間
+    pub divider: u8,
+    pub decay: u8
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope{start: false, loop_flag: false, constant_volume: false, volume: 0, divider: 0, decay: 0}
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.loop_flag = (value & 0x20) > 0;
+        self.constant_volume = (value & 0x10) > 0;
+        self.volume = value & 0x0F;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+            return;
+        }
+        if self.divider > 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.volume;
+        if self.decay > 0 {
+            self.decay -= 1;
+        } else if self.loop_flag {
+            self.decay = 15;
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.constant_volume {self.volume} else {self.decay}
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.start);
+        w.write_bool(self.loop_flag);
+        w.write_bool(self.constant_volume);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.start = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.constant_volume = r.read_bool()?;
+        self.volume = r.read_u8()? & 0x0F;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Envelope {
+        Envelope::new()
+    }
+}
+
+// 矩形波チャンネル
+pub struct Pulse {
+    pub ones_complement: bool, // 矩形波1はスイープの減算が1の補数になる
+    pub enabled: bool,
+    pub duty: u8,
+    pub sequence_pos: u8,
+    pub timer_period: u16,
+    pub timer: u16,
+    pub length_counter: u8,
+    pub envelope: Envelope,
+    pub sweep_enabled: bool,
+    pub sweep_period: u8,
+    pub sweep_negate: bool,
+    pub sweep_shift: u8,
+    pub sweep_divider: u8,
+    pub sweep_reload: bool
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse{
+            ones_complement,
+            enabled: false,
+            duty: 0,
+            sequence_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            length_counter: 0,
+            envelope: Envelope::new(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false
+        }
+    }
+
+    // $4000-$4003/$4004-$4007 registerはその中の0-3
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.duty = value >> 6;
+                self.envelope.write(value);
+            },
+            1 => {
+                self.sweep_enabled = (value & 0x80) > 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = (value & 0x08) > 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            },
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | value as u16;
+            },
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((value & 0x07) as u16) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(value >> 3) as usize];
+                }
+                self.sequence_pos = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // APUサイクル(CPU 2サイクル)ごとに呼ばれる
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_pos = (self.sequence_pos + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn target_period(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if !self.sweep_negate {
+            return self.timer_period + change;
+        }
+        if self.ones_complement {
+            self.timer_period.wrapping_sub(change + 1)
+        } else {
+            self.timer_period.wrapping_sub(change)
+        }
+    }
+
+    // 周期が短すぎるか、スイープ先が$7FFを超える場合は(スイープ無効でも)消音される
+    fn is_sweep_muted(&self) -> bool {
+        self.timer_period < 8 || self.target_period() > 0x7FF
+    }
+
+    // 1/2フレームごとに長さカウンタとスイープを進める
+    pub fn clock_half_frame(&mut self) {
+        if !self.envelope.loop_flag && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.is_sweep_muted() {
+            self.timer_period = self.target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if self.length_counter == 0 || self.is_sweep_muted() || DUTY_TABLE[self.duty as usize][self.sequence_pos as usize] == 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_u8(self.duty);
+        w.write_u8(self.sequence_pos);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.length_counter);
+        self.envelope.save_state(w);
+        w.write_bool(self.sweep_enabled);
+        w.write_u8(self.sweep_period);
+        w.write_bool(self.sweep_negate);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_bool(self.sweep_reload);
+    }
+
+    // テーブルの添字になる値は範囲外なら壊れたデータとして扱う
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.duty = r.read_u8()?;
+        self.sequence_pos = r.read_u8()?;
+        if self.duty >= 4 || self.sequence_pos >= 8 {
+            return Err(StateError::Corrupted);
+        }
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.length_counter = r.read_u8()?;
+        self.envelope.load_state(r)?;
+        self.sweep_enabled = r.read_bool()?;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_bool()?;
+        self.sweep_shift = r.read_u8()? & 0x07;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_bool()?;
+        Ok(())
+    }
+}
+
+// 三角波チャンネル 長さカウンタとは別に線形カウンタで音長を決める
+pub struct Triangle {
+    pub enabled: bool,
+    pub control: bool, // 長さカウンタ停止 兼 線形カウンタの再読み込みフラグを保持
+    pub linear_reload_value: u8,
+    pub linear_counter: u8,
+    pub linear_reload: bool,
+    pub sequence_pos: u8,
+    pub timer_period: u16,
+    pub timer: u16,
+    pub length_counter: u8
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle{
+            enabled: false,
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+            sequence_pos: 0,
+            timer_period: 0,
+            timer: 0,
+            length_counter: 0
+        }
+    }
+
+    // $4008-$400B registerはその中の0-3
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = (value & 0x80) > 0;
+                self.linear_reload_value = value & 0x7F;
+            },
+            2 => {
+                self.timer_period = (self.timer_period & 0x0700) | value as u16;
+            },
+            3 => {
+                self.timer_period = (self.timer_period & 0x00FF) | (((value & 0x07) as u16) << 8);
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(value >> 3) as usize];
+                }
+                self.linear_reload = true;
+            },
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // CPU 1サイクルごとに呼ばれる
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period;
+        if self.linear_counter > 0 && self.length_counter > 0 {
+            self.sequence_pos = (self.sequence_pos + 1) & 0x1F;
+        }
+    }
+
+    pub fn clock_quarter_frame(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        if !self.control && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    // 0-15 停止中も最後の値を出力し続ける
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.sequence_pos as usize]
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.control);
+        w.write_u8(self.linear_reload_value);
+        w.write_u8(self.linear_counter);
+        w.write_bool(self.linear_reload);
+        w.write_u8(self.sequence_pos);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.length_counter);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.control = r.read_bool()?;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_bool()?;
+        self.sequence_pos = r.read_u8()?;
+        if self.sequence_pos >= 32 {
+            return Err(StateError::Corrupted);
+        }
+        self.timer_period = r.read_u16()?;
+        self.timer = r.read_u16()?;
+        self.length_counter = r.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Default for Triangle {
+    fn default() -> Triangle {
+        Triangle::new()
+    }
+}
+
+// ノイズチャンネル 15bitのLFSR
+pub struct Noise {
+    pub enabled: bool,
+    pub short_mode: bool, // bit6をフィードバックに使う93bit周期のモード
+    pub timer_period: u16,
+    pub timer: u16,
+    pub shift_register: u16,
+    pub length_counter: u8,
+    pub envelope: Envelope
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise{
+            enabled: false,
+            short_mode: false,
+            timer_period: NOISE_PERIOD_TABLE[0],
+            timer: 0,
+            shift_register: 1,
+            length_counter: 0,
+            envelope: Envelope::new()
+        }
+    }
+
+    // $400C-$400F registerはその中の0-3
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => self.envelope.write(value),
+            2 => {
+                self.short_mode = (value & 0x80) > 0;
+                self.timer_period = NOISE_PERIOD_TABLE[(value & 0x0F) as usize];
+            },
+            3 => {
+                if self.enabled {
+                    self.length_counter = LENGTH_TABLE[(value >> 3) as usize];
+                }
+                self.envelope.start = true;
+            },
+            _ => {}
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.length_counter = 0;
+        }
+    }
+
+    // CPU 1サイクルごとに呼ばれる
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+        let tap = if self.short_mode {6} else {1};
+        let feedback = (self.shift_register ^ (self.shift_register >> tap)) & 0x01;
+        self.shift_register = (self.shift_register >> 1) | (feedback << 14);
+    }
+
+    pub fn clock_half_frame(&mut self) {
+        if !self.envelope.loop_flag && self.length_counter > 0 {
+            self.length_counter -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if self.length_counter == 0 || (self.shift_register & 0x01) > 0 {
+            return 0;
+        }
+        self.envelope.output()
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.enabled);
+        w.write_bool(self.short_mode);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u16(self.shift_register);
+        w.write_u8(self.length_counter);
+        self.envelope.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_bool()?;
+        self.short_mode = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        if !NOISE_PERIOD_TABLE.contains(&self.timer_period) {
+            return Err(StateError::Corrupted);
+        }
+        self.timer = r.read_u16()?;
+        self.shift_register = r.read_u16()? & 0x7FFF;
+        self.length_counter = r.read_u8()?;
+        self.envelope.load_state(r)
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Noise {
+        Noise::new()
+    }
+}
+
+// デルタ変調チャンネル CPUのメモリからサンプルを1バイトずつ読み込んで1bitずつ出力値を増減する
+pub struct Dmc {
+    pub irq_enabled: bool,
+    pub irq: bool,
+    pub loop_flag: bool,
+    pub timer_period: u16,
+    pub timer: u16,
+    pub output_level: u8,
+    pub sample_address: u16,
+    pub sample_length: u16,
+    pub current_address: u16,
+    pub bytes_remaining: u16,
+    pub sample_buffer: Option<u8>,
+    pub shift_register: u8,
+    pub bits_remaining: u8,
+    pub silence: bool
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc{
+            irq_enabled: false,
+            irq: false,
+            loop_flag: false,
+            timer_period: DMC_RATE_TABLE[0],
+            timer: 0,
+            output_level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true
+        }
+    }
+
+    // $4010-$4013 registerはその中の0-3
+    pub fn write_register(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.irq_enabled = (value & 0x80) > 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.loop_flag = (value & 0x40) > 0;
+                self.timer_period = DMC_RATE_TABLE[(value & 0x0F) as usize];
+            },
+            1 => self.output_level = value & 0x7F,
+            2 => self.sample_address = 0xC000 | ((value as u16) << 6),
+            _ => self.sample_length = ((value as u16) << 4) | 1
+        }
+    }
+
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // サンプルバッファが空で読み込むべきバイトが残っていればそのアドレス
+    pub fn pending_read(&self) -> Option<u16> {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    // pending_readのアドレスから読んだ値を受け取る
+    pub fn fill_sample_buffer(&mut self, value: u8) {
+        self.sample_buffer = Some(value);
+        // $FFFFの次は$8000に戻る
+        self.current_address = if self.current_address == 0xFFFF {0x8000} else {self.current_address + 1};
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // CPU 1サイクルごとに呼ばれる
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+        if !self.silence {
+            if (self.shift_register & 0x01) > 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.silence = false;
+                    self.shift_register = sample;
+                },
+                None => self.silence = true
+            }
+        }
+    }
+
+    // 0-127
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq);
+        w.write_bool(self.loop_flag);
+        w.write_u16(self.timer_period);
+        w.write_u16(self.timer);
+        w.write_u8(self.output_level);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.current_address);
+        w.write_u16(self.bytes_remaining);
+        w.write_bool(self.sample_buffer.is_some());
+        w.write_u8(self.sample_buffer.unwrap_or(0));
+        w.write_u8(self.shift_register);
+        w.write_u8(self.bits_remaining);
+        w.write_bool(self.silence);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = r.read_bool()?;
+        self.irq = r.read_bool()?;
+        self.loop_flag = r.read_bool()?;
+        self.timer_period = r.read_u16()?;
+        if !DMC_RATE_TABLE.contains(&self.timer_period) {
+            return Err(StateError::Corrupted);
+        }
+        self.timer = r.read_u16()?;
+        self.output_level = r.read_u8()? & 0x7F;
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.current_address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let has_sample = r.read_bool()?;
+        let sample = r.read_u8()?;
+        self.sample_buffer = if has_sample {Some(sample)} else {None};
+        self.shift_register = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?;
+        if !(1..=8).contains(&self.bits_remaining) {
+            return Err(StateError::Corrupted);
+        }
+        self.silence = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Dmc {
+    fn default() -> Dmc {
+        Dmc::new()
+    }
+}
+
+pub struct Apu {
+    pub pulse1: Pulse,
+    pub pulse2: Pulse,
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    pub five_step_mode: bool,
+    pub frame_irq_inhibit: bool,
+    pub frame_irq: bool,
+    pub frame_cycle: u64, // フレームシーケンサのCPUサイクル
+    pub odd_cycle: bool // 矩形波はAPUサイクル(CPU 2サイクル)ごとに進む
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu{
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+            five_step_mode: false,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            odd_cycle: false
+        }
+    }
+
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write_register(address - 0x4000, value),
+            0x4004..=0x4007 => self.pulse2.write_register(address - 0x4004, value),
+            0x4008..=0x400B => self.triangle.write_register(address - 0x4008, value),
+            0x400C..=0x400F => self.noise.write_register(address - 0x400C, value),
+            0x4010..=0x4013 => self.dmc.write_register(address - 0x4010, value),
+            0x4015 => {
+                self.pulse1.set_enabled((value & 0x01) > 0);
+                self.pulse2.set_enabled((value & 0x02) > 0);
+                self.triangle.set_enabled((value & 0x04) > 0);
+                self.noise.set_enabled((value & 0x08) > 0);
+                self.dmc.set_enabled((value & 0x10) > 0);
+            },
+            0x4017 => {
+                self.five_step_mode = (value & 0x80) > 0;
+                self.frame_irq_inhibit = (value & 0x40) > 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq = false;
+                }
+                // 書き込みでシーケンサはリセットされ、5ステップモードなら即座に1/4・1/2フレームのクロックが入る
+                self.frame_cycle = 0;
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            },
+            _ => {}
+        }
+    }
+
+    // $4015 bit0-4: 各チャンネルが動作中か bit6: フレームIRQ bit7: DMC IRQ
+    // 読み込みでフレームIRQはクリアされる
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length_counter > 0 {
+            status |= 0x01;
+        }
+        if self.pulse2.length_counter > 0 {
+            status |= 0x02;
+        }
+        if self.triangle.length_counter > 0 {
+            status |= 0x04;
+        }
+        if self.noise.length_counter > 0 {
+            status |= 0x08;
+        }
+        if self.dmc.bytes_remaining > 0 {
+            status |= 0x10;
+        }
+        if self.frame_irq {
+            status |= 0x40;
+        }
+        if self.dmc.irq {
+            status |= 0x80;
+        }
+        self.frame_irq = false;
+        status
+    }
+
+    // CPU 1サイクルごとに呼ばれる
+    pub fn clock(&mut self) {
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        self.clock_frame_sequencer();
+    }
+
+    // 全チャンネルをミックスした出力 0.0-1.0程度
+    pub fn output(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let tnd = 3 * self.triangle.output() as usize + 2 * self.noise.output() as usize + self.dmc.output() as usize;
+        PULSE_MIX_TABLE[pulse as usize] + TND_MIX_TABLE[tnd]
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        self.frame_cycle += 1;
+        if let Some(step) = FRAME_STEP_CYCLES.iter().position(|&c| c == self.frame_cycle) {
+            if step == 3 && self.five_step_mode {
+                // 5ステップモードの4ステップ目は何もしない
+                return;
+            }
+            self.clock_quarter_frame();
+            if step == 1 || step == 3 {
+                self.clock_half_frame();
+            }
+            if step == 3 && !self.frame_irq_inhibit {
+                self.frame_irq = true;
+            }
+        }
+        if self.five_step_mode && self.frame_cycle == FRAME_STEP5_CYCLE {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+        let frame_cycles = if self.five_step_mode {FRAME_CYCLES_5_STEP} else {FRAME_CYCLES_4_STEP};
+        if self.frame_cycle == frame_cycles {
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_quarter_frame();
+        self.noise.envelope.clock();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.clock_half_frame();
+        self.pulse2.clock_half_frame();
+        self.triangle.clock_half_frame();
+        self.noise.clock_half_frame();
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        self.pulse1.save_state(w);
+        self.pulse2.save_state(w);
+        self.triangle.save_state(w);
+        self.noise.save_state(w);
+        self.dmc.save_state(w);
+        w.write_bool(self.five_step_mode);
+        w.write_bool(self.frame_irq_inhibit);
+        w.write_bool(self.frame_irq);
+        w.write_u64(self.frame_cycle);
+        w.write_bool(self.odd_cycle);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.load_state(r)?;
+        self.pulse2.load_state(r)?;
+        self.triangle.load_state(r)?;
+        self.noise.load_state(r)?;
+        self.dmc.load_state(r)?;
+        self.five_step_mode = r.read_bool()?;
+        self.frame_irq_inhibit = r.read_bool()?;
+        self.frame_irq = r.read_bool()?;
+        self.frame_cycle = r.read_u64()?;
+        self.odd_cycle = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+
+pub const CPU_CLOCK_HZ: f64 = 1_789_773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// 1次のハイパス/ローパスフィルタ
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    prev_input: f32,
+    prev_output: f32
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff_hz: f32, sample_rate: u32) -> Filter {
+        let rc = 1.0 / (2.0 * PI * cutoff_hz);
+        let dt = 1.0 / sample_rate as f32;
+        let alpha = if high_pass {rc / (rc + dt)} else {dt / (rc + dt)};
+        Filter{high_pass, alpha, prev_input: 0.0, prev_output: 0.0}
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.prev_output + input - self.prev_input)
+        } else {
+            self.prev_output + self.alpha * (input - self.prev_output)
+        };
+        self.prev_input = input;
+        self.prev_output = output;
+        output
+    }
+}
+
+// 2次のIIRフィルタ(ローパス) CPUクロックのような高いレートで低いカットオフを扱うのでf64で計算する
+struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    x1: f64,
+    x2: f64,
+    y1: f64,
+    y2: f64
+}
+
+impl Biquad {
+    fn low_pass(cutoff_hz: f64, sample_rate_hz: f64, q: f64) -> Biquad {
+        let w0 = 2.0 * std::f64::consts::PI * cutoff_hz / sample_rate_hz;
+        let alpha = w0.sin() / (2.0 * q);
+        let a0 = 1.0 + alpha;
+        let b1 = (1.0 - w0.cos()) / a0;
+        Biquad{
+            b0: b1 / 2.0,
+            b1,
+            b2: b1 / 2.0,
+            a1: -2.0 * w0.cos() / a0,
+            a2: (1.0 - alpha) / a0,
+            x1: 0.0,
+            x2: 0.0,
+            y1: 0.0,
+            y2: 0.0
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.b0 * input + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = input;
+        self.y2 = self.y1;
+        self.y1 = output;
+        output
+    }
+}
+
+// 間引く前にかけるローパスのカットオフ 出力のナイキスト周波数より少し下に置く
+const ANTI_ALIAS_CUTOFF_RATIO: f64 = 0.4;
+// 6次のバターワース特性になる各段のQ
+const ANTI_ALIAS_Q: [f64; 3] = [0.5176, std::f64::consts::FRAC_1_SQRT_2, 1.9319];
+
+// APUの出力をCPUクロックから出力サンプリングレートへ変換してためておく
+// CPUクロックのままローパスで出力のナイキスト周波数以上を落としてから間引き、折り返しを防ぐ
+pub struct AudioOutput {
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    cycle_remainder: f64,
+    anti_alias: [Biquad; 3],
+    filters: [Filter; 3],
+    samples: VecDeque<f32>
+}
+
+impl AudioOutput {
+    pub fn new(sample_rate: u32) -> AudioOutput {
+        AudioOutput{
+            sample_rate,
+            cycles_per_sample: CPU_CLOCK_HZ / sample_rate as f64,
+            cycle_remainder: 0.0,
+            anti_alias: [
+                Biquad::low_pass(sample_rate as f64 * ANTI_ALIAS_CUTOFF_RATIO, CPU_CLOCK_HZ, ANTI_ALIAS_Q[0]),
+                Biquad::low_pass(sample_rate as f64 * ANTI_ALIAS_CUTOFF_RATIO, CPU_CLOCK_HZ, ANTI_ALIAS_Q[1]),
+                Biquad::low_pass(sample_rate as f64 * ANTI_ALIAS_CUTOFF_RATIO, CPU_CLOCK_HZ, ANTI_ALIAS_Q[2])
+            ],
+            // 実機の出力段 90Hzと440Hzのハイパス、14kHzのローパス
+            filters: [
+                Filter::new(true, 90.0, sample_rate),
+                Filter::new(true, 440.0, sample_rate),
+                Filter::new(false, 14000.0, sample_rate)
+            ],
+            samples: VecDeque::new()
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    // 出力側の消費ペースに合わせて生成するサンプル数をratio倍にする
+    // 再生側のバッファが溢れたり枯渇したりしないよう、1から僅かにずらして使う
+    pub fn set_rate_adjust(&mut self, ratio: f64) {
+        self.cycles_per_sample = CPU_CLOCK_HZ / (self.sample_rate as f64 * ratio);
+    }
+
+    // CPU 1サイクルごとにミキサーの出力(0.0-1.0)を渡す
+    pub fn push(&mut self, value: f32) {
+        let mut filtered = value as f64;
+        for biquad in self.anti_alias.iter_mut() {
+            filtered = biquad.process(filtered);
+        }
+        self.cycle_remainder += 1.0;
+        if self.cycle_remainder < self.cycles_per_sample {
+            return;
+        }
+        self.cycle_remainder -= self.cycles_per_sample;
+        let mut sample = filtered as f32;
+        for filter in self.filters.iter_mut() {
+            sample = filter.process(sample);
+        }
+        // 取り出されないまま1秒分を超えたら古いものから捨てる
+        if self.samples.len() >= self.sample_rate as usize {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    pub fn drain(&mut self) -> Vec<f32> {
+        self.samples.drain(..).collect()
+    }
+}
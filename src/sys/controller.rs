@@ -0,0 +1,70 @@
+use super::state::{StateError, StateReader, StateWriter};
+
+// ボタンのビット配置 $4016/$4017から読み出される順番と同じ
+pub const BUTTON_A: u8 = 0x01;
+pub const BUTTON_B: u8 = 0x02;
+pub const BUTTON_SELECT: u8 = 0x04;
+pub const BUTTON_START: u8 = 0x08;
+pub const BUTTON_UP: u8 = 0x10;
+pub const BUTTON_DOWN: u8 = 0x20;
+pub const BUTTON_LEFT: u8 = 0x40;
+pub const BUTTON_RIGHT: u8 = 0x80;
+
+// 標準コントローラ 8bitのパラレル入力シフトレジスタ
+pub struct Controller {
+    pub buttons: u8,
+    pub shift_register: u8,
+    pub strobe: bool
+}
+
+impl Controller {
+    pub fn new() -> Controller {
+        Controller{buttons: 0, shift_register: 0, strobe: false}
+    }
+
+    pub fn set_buttons(&mut self, buttons: u8){
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift_register = buttons;
+        }
+    }
+
+    // $4016への書き込み bit0が1の間はボタンの状態をラッチし続ける
+    pub fn write_strobe(&mut self, value: u8){
+        self.strobe = (value & 0x01) > 0;
+        if self.strobe {
+            self.shift_register = self.buttons;
+        }
+    }
+
+    pub fn read(&mut self) -> u8{
+        // 上位ビットはオープンバス 直前にバスに乗っていたアドレス上位バイト($40)が見える
+        const OPEN_BUS: u8 = 0x40;
+        if self.strobe {
+            return OPEN_BUS | (self.buttons & 0x01);
+        }
+        let value = self.shift_register & 0x01;
+        // 8回読んだ後は1が返る
+        self.shift_register = (self.shift_register >> 1) | 0x80;
+        OPEN_BUS | value
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.write_u8(self.buttons);
+        w.write_u8(self.shift_register);
+        w.write_bool(self.strobe);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.buttons = r.read_u8()?;
+        self.shift_register = r.read_u8()?;
+        self.strobe = r.read_bool()?;
+        Ok(())
+    }
+}
+
+impl Default for Controller {
+    fn default() -> Controller {
+        Controller::new()
+    }
+}
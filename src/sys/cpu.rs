@@ -1,6 +1,6 @@
 use std::convert::TryInto;
 
-use super::{memory_map::MemoryMap};
+use super::{memory_map::MemoryMap, state::{StateError, StateReader, StateWriter}};
 
 pub struct Cpu {
     pub program_counter: u32,
@@ -8,9 +8,26 @@ pub struct Cpu {
     pub reg_x: u8,
     pub reg_y: u8,
     pub reg_s: u8,
-    pub reg_p: u8
+    pub reg_p: u8,
+    pub irq_line: u8, // 各IRQ要因のORをとったレベルトリガの信号線
+    pub nmi_pending: bool,
+    pub irq_inhibit: bool, // 直前の割り込みポーリング時点のIフラグ
+    pub skip_interrupt_poll: bool,
+    pub pending_vector: Option<u32>, // 割り込みシーケンスで読み込み待ちのベクタ
+    pub halted: bool // KILで停止している リセットでのみ解除される
 }
 
+// IRQ要因 irq_lineの各ビットに対応
+pub const IRQ_FRAME_COUNTER: u8 = 0x01;
+pub const IRQ_DMC: u8 = 0x02;
+pub const IRQ_MAPPER: u8 = 0x04;
+
+const NMI_VECTOR: u32 = 0xFFFA;
+const IRQ_VECTOR: u32 = 0xFFFE;
+
+// XAA・LAX #immでAとORをとる不定の値 個体差があるため一般的な値を使う
+const UNSTABLE_MAGIC: u8 = 0xEE;
+
 pub enum Addressing {
     Implied,
     Accumulator,
@@ -36,16 +53,98 @@ impl Cpu{
         let reg_y: u8 = 0;
         let reg_s: u8 = 0;
         let reg_p: u8 = 0;
-        Cpu{program_counter, reg_a, reg_x, reg_y, reg_s, reg_p}
+        Cpu{program_counter, reg_a, reg_x, reg_y, reg_s, reg_p, irq_line: 0, nmi_pending: false, irq_inhibit: true, skip_interrupt_poll: false, pending_vector: None, halted: false}
     }
 
     pub fn init(&mut self){
         self.reg_p = 0x34;
         self.reg_s = 0xFD;
+        self.nmi_pending = false;
+        self.irq_inhibit = true;
+        self.skip_interrupt_poll = false;
+        self.pending_vector = None;
+        self.halted = false;
     }
-    
-    pub fn next_cycle(&mut self, memory_map: &mut MemoryMap){
-        self.interpret(memory_map.get_from_address(self.program_counter), memory_map);
+
+    pub fn set_irq(&mut self, source: u8, level: bool){
+        if level {
+            self.irq_line |= source;
+        }
+        else {
+            self.irq_line &= !source;
+        }
+    }
+
+    pub fn is_irq_asserted(&self) -> bool{
+        self.irq_line != 0
+    }
+
+    // 割り込みが保留されていれば割り込みシーケンスを、そうでなければ1命令を実行し、消費したサイクル数を返す
+    pub fn next_cycle(&mut self, memory_map: &mut MemoryMap) -> u8{
+        let cycles = self.start_next_cycle(memory_map);
+        self.finish_interrupt(memory_map);
+        cycles
+    }
+
+    // next_cycleと同じだが、BRK/IRQ/NMIのベクタ読み込みはfinish_interruptを呼ぶまで遅らせる
+    // 間でPPUを4サイクル分進めると、シーケンス中に発生したNMIによる乗っ取りを再現できる
+    pub fn start_next_cycle(&mut self, memory_map: &mut MemoryMap) -> u8{
+        if self.halted {
+            // 停止中は割り込みも受け付けず、1サイクルずつ時間だけ進める
+            return 1;
+        }
+        if self.skip_interrupt_poll {
+            self.skip_interrupt_poll = false;
+        }
+        else if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(self.program_counter, false, NMI_VECTOR, memory_map);
+            self.irq_inhibit = true;
+            return 7;
+        }
+        else if self.is_irq_asserted() && !self.irq_inhibit {
+            self.interrupt(self.program_counter, false, IRQ_VECTOR, memory_map);
+            self.irq_inhibit = true;
+            return 7;
+        }
+
+        let opcode = memory_map.get_from_address(self.program_counter);
+        let flag_i = self.get_flag_i();
+        let cycles = self.interpret(opcode, memory_map);
+        self.irq_inhibit = match opcode {
+            // CLI/SEI/PLPによるIフラグの変更は次の命令の後のポーリングから反映される
+            0x58 | 0x78 | 0x28 => flag_i,
+            _ => self.get_flag_i()
+        };
+        // ページを跨がない分岐成立時は最終サイクルでポーリングしないため割り込みが1命令遅れる
+        self.skip_interrupt_poll = (opcode & 0x1F) == 0x10 && cycles == 3;
+        cycles
+    }
+
+    // PCとPをスタックに積みベクタへジャンプする BRK/IRQ/NMI共通
+    fn interrupt(&mut self, return_address: u32, flag_b: bool, vector: u32, memory_map: &mut MemoryMap){
+        push_stack(self, (return_address >> 8) as u8, memory_map);
+        push_stack(self, return_address as u8, memory_map);
+        let value = if flag_b {self.reg_p | 0x30} else {(self.reg_p & 0xEF) | 0x20};
+        push_stack(self, value, memory_map);
+        self.set_flag_i(true);
+        self.pending_vector = Some(vector);
+    }
+
+    // 割り込みシーケンスの残り(5サイクル目以降)のベクタ読み込み シーケンス中でなければ何もしない
+    pub fn finish_interrupt(&mut self, memory_map: &mut MemoryMap){
+        let vector = match self.pending_vector.take() {
+            Some(vector) => vector,
+            None => return
+        };
+        // ベクタ読み込みまでにNMIが発生した場合はNMIのベクタに乗っ取られる
+        let vector = if self.nmi_pending {
+            self.nmi_pending = false;
+            NMI_VECTOR
+        } else {
+            vector
+        };
+        self.program_counter = memory_map.get_from_address16(vector) as u32;
     }
 
     pub fn set_flag_i(&mut self, value: bool){
@@ -142,6 +241,20 @@ impl Cpu{
         return address;
     }
 
+    pub fn is_page_crossed(&self, addressing: &Addressing, memory_map: &mut MemoryMap) -> bool{
+        let base_address: u16 = match addressing{
+            Addressing::AbsoluteX | Addressing::AbsoluteY =>
+                self.getIm16(memory_map),
+            Addressing::Indirect_Y => {
+                let tmp_address = self.getIm8(memory_map) & 0xFF;
+                memory_map.get_from_address16_by_address8(tmp_address)
+            },
+            _ => return false
+        };
+        let address = self.get_operand_address(addressing, memory_map) as u16;
+        (base_address & 0xFF00) != (address & 0xFF00)
+    }
+
     pub fn get_operand(&self, addressing: &Addressing, memory_map: &mut MemoryMap) -> u8{
         let data: u8 = match addressing{
             Addressing::Immediate =>
@@ -581,6 +694,79 @@ impl Cpu{
         self.eval_NZ(operand);
         self.reg_x = operand;
     }
+
+    // ANC AND #immの結果のbit7をCにも入れる ※拡張命令
+    pub fn op_anc(&mut self, memory_map: &mut MemoryMap){
+        self.op_and(&Addressing::Immediate, memory_map);
+        self.set_flag_c(self.get_flag_n());
+    }
+
+    // ALR AND #immの後にLSR A ※拡張命令
+    pub fn op_alr(&mut self, memory_map: &mut MemoryMap){
+        self.op_and(&Addressing::Immediate, memory_map);
+        self.op_lsr();
+    }
+
+    // ARR AND #immの後にROR A CとVは結果のbit6・bit5から決まる ※拡張命令
+    pub fn op_arr(&mut self, memory_map: &mut MemoryMap){
+        self.op_and(&Addressing::Immediate, memory_map);
+        self.op_ror();
+        let bit6 = (self.reg_a >> 6) & 0x01;
+        let bit5 = (self.reg_a >> 5) & 0x01;
+        self.set_flag_c(bit6 > 0);
+        self.set_flag_v((bit6 ^ bit5) > 0);
+    }
+
+    // XAA(ANE) ※拡張命令
+    pub fn op_xaa(&mut self, memory_map: &mut MemoryMap){
+        let value = self.get_operand(&Addressing::Immediate, memory_map);
+        self.reg_a = (self.reg_a | UNSTABLE_MAGIC) & self.reg_x & value;
+        self.eval_NZ(self.reg_a);
+    }
+
+    // LAX #imm(LXA) ※拡張命令
+    pub fn op_lax_immediate(&mut self, memory_map: &mut MemoryMap){
+        let value = self.get_operand(&Addressing::Immediate, memory_map);
+        self.reg_a = (self.reg_a | UNSTABLE_MAGIC) & value;
+        self.reg_x = self.reg_a;
+        self.eval_NZ(self.reg_a);
+    }
+
+    // AXS(SBX) XにA AND Xから#immを引いた値を入れる Cは比較と同じ ※拡張命令
+    pub fn op_axs(&mut self, memory_map: &mut MemoryMap){
+        let value = self.get_operand(&Addressing::Immediate, memory_map);
+        let and_value = self.reg_a & self.reg_x;
+        self.set_flag_c(and_value >= value);
+        self.reg_x = and_value.wrapping_sub(value);
+        self.eval_NZ(self.reg_x);
+    }
+
+    // LAS メモリとSのANDをA・X・Sに入れる ※拡張命令
+    pub fn op_las(&mut self, addressing: &Addressing, memory_map: &mut MemoryMap){
+        let value = self.get_operand(addressing, memory_map) & self.reg_s;
+        self.reg_a = value;
+        self.reg_x = value;
+        self.reg_s = value;
+        self.eval_NZ(value);
+    }
+
+    // SHA・SHX・SHY・TAS共通 valueと(ベースアドレスの上位バイト+1)のANDを書き込む ※拡張命令
+    // インデックスでページを跨いだ場合は書き込み先の上位バイトも書き込む値に置き換わる
+    pub fn op_sh(&self, addressing: &Addressing, value: u8, memory_map: &mut MemoryMap){
+        let address = self.get_operand_address(addressing, memory_map);
+        let index = match addressing {
+            Addressing::AbsoluteX => self.reg_x,
+            _ => self.reg_y
+        };
+        let base_high = ((address as u16).wrapping_sub(index as u16) >> 8) as u8;
+        let data = value & base_high.wrapping_add(1);
+        let address = if (address >> 8) as u8 != base_high {
+            ((data as u32) << 8) | (address & 0xFF)
+        } else {
+            address
+        };
+        memory_map.set_from_address(address, data);
+    }
     pub fn op_ldy(&mut self, addressing: &Addressing, memory_map: &mut MemoryMap){
         let operand: u8 = self.get_operand(addressing, memory_map);
         self.eval_NZ(operand);
@@ -593,69 +779,49 @@ impl Cpu{
         self.reg_x = operand;
     }
 
-    pub fn op_bne(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_bne(&mut self, memory_map: &mut MemoryMap) -> u8{
         let zero_flag: bool = self.get_flag_z();
-        if !zero_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
-        }
+        self.op_branch(!zero_flag, memory_map)
     }
-    pub fn op_bpl(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_bpl(&mut self, memory_map: &mut MemoryMap) -> u8{
         let negative_flag: bool = self.get_flag_n();
-        if !negative_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
-        }
+        self.op_branch(!negative_flag, memory_map)
     }
-    pub fn op_bcc(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_bcc(&mut self, memory_map: &mut MemoryMap) -> u8{
         let carry_flag: bool = self.get_flag_c();
-        if !carry_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
-        }
+        self.op_branch(!carry_flag, memory_map)
     }
-    pub fn op_bcs(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_bcs(&mut self, memory_map: &mut MemoryMap) -> u8{
         let carry_flag: bool = self.get_flag_c();
-        if carry_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
-        }
+        self.op_branch(carry_flag, memory_map)
     }
-    pub fn op_bvs(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_bvs(&mut self, memory_map: &mut MemoryMap) -> u8{
         let overflow_flag: bool = self.get_flag_v();
-        if overflow_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
-        }
+        self.op_branch(overflow_flag, memory_map)
     }
-    pub fn op_bvc(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_bvc(&mut self, memory_map: &mut MemoryMap) -> u8{
         let overflow_flag: bool = self.get_flag_v();
-        if !overflow_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
-        }
+        self.op_branch(!overflow_flag, memory_map)
     }
-    pub fn op_bmi(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_bmi(&mut self, memory_map: &mut MemoryMap) -> u8{
         let negative_flag: bool = self.get_flag_n();
-        if negative_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
-        }
+        self.op_branch(negative_flag, memory_map)
     }
-    pub fn op_beq(&mut self, memory_map: &mut MemoryMap){
+    pub fn op_beq(&mut self, memory_map: &mut MemoryMap) -> u8{
         let zero_flag: bool = self.get_flag_z();
-        if zero_flag {
-            let relative: u8 = self.getIm8(memory_map);
-            let relative_ = if relative > 0x80 {relative as i16 - 0x100 as i16} else {relative as i16};
-            self.program_counter = (self.program_counter as i32 + relative_ as i32) as u32;
+        self.op_branch(zero_flag, memory_map)
+    }
+
+    // 分岐成立で+1サイクル、分岐先が次の命令と別ページなら更に+1サイクル
+    fn op_branch(&mut self, condition: bool, memory_map: &mut MemoryMap) -> u8{
+        if !condition {
+            return 0;
         }
+        let relative = self.getIm8(memory_map) as i8;
+        let next_address = self.program_counter + 2;
+        self.program_counter = (self.program_counter as i32 + relative as i32) as u32;
+        let branch_address = self.program_counter + 2;
+        if (next_address & 0xFF00) != (branch_address & 0xFF00) {2} else {1}
     }
 
     pub fn op_jsr(&mut self, memory_map: &mut MemoryMap){
@@ -724,9 +890,9 @@ impl Cpu{
         self.reg_s = (self.reg_s + 1) as u8;
     }
 
-    pub fn op_brk(&mut self){
-        self.set_flag_i(true);
-        self.set_flag_b(true);
+    pub fn op_brk(&mut self, memory_map: &mut MemoryMap){
+        // BRKの次の1バイトはパディングとして読み飛ばされるのでPC+2をpush
+        self.interrupt(self.program_counter + 2, true, IRQ_VECTOR, memory_map);
     }
 
     pub fn opJMP_Abs(&mut self, memory_map: &mut MemoryMap){
@@ -745,10 +911,21 @@ impl Cpu{
         self.reg_p = value;
     }
 
-    pub fn interpret(&mut self, opcode: u8, memory_map: &mut MemoryMap){
+    // 1命令を実行し、消費したサイクル数を返す
+    pub fn interpret(&mut self, opcode: u8, memory_map: &mut MemoryMap) -> u8{
 
         let opcode: u8 = opcode & 0xFF;
+        let mut cycles = CYCLE_TABLE[opcode as usize];
+        if let Some(addressing) = page_cross_addressing(opcode) {
+            if self.is_page_crossed(&addressing, memory_map) {
+                cycles += 1;
+            }
+        }
         match(opcode){
+            0x00 =>//BRK:ソフトウェア割り込み(1バイト/7サイクル)
+            {
+                self.op_brk(memory_map);
+            },
             0xA2 =>//LDX(Immediate):メモリからXにロード(2バイト/2サイクル)
             {
                 self.op_ldx(&Addressing::Immediate, memory_map);
@@ -1437,42 +1614,42 @@ impl Cpu{
             },
             0xD0 =>
             {
-                self.op_bne(memory_map);
+                cycles += self.op_bne(memory_map);
                 self.program_counter += 2;
             },
             0x10 =>
             {
-                self.op_bpl(memory_map);
+                cycles += self.op_bpl(memory_map);
                 self.program_counter += 2;
             },
             0x90 =>
             {
-                self.op_bcc(memory_map);
+                cycles += self.op_bcc(memory_map);
                 self.program_counter += 2;
             },
             0xB0 =>
             {
-                self.op_bcs(memory_map);
+                cycles += self.op_bcs(memory_map);
                 self.program_counter += 2;
             },
             0x70 =>
             {
-                self.op_bvs(memory_map);
+                cycles += self.op_bvs(memory_map);
                 self.program_counter += 2;
             },
             0x50 =>
             {
-                self.op_bvc(memory_map);
+                cycles += self.op_bvc(memory_map);
                 self.program_counter += 2;
             },
             0x30 =>
             {
-                self.op_bmi(memory_map);
+                cycles += self.op_bmi(memory_map);
                 self.program_counter += 2;
             },
             0xF0 =>
             {
-                self.op_beq(memory_map);
+                cycles += self.op_beq(memory_map);
                 self.program_counter += 2;
             },
             0x20 =>
@@ -1802,6 +1979,73 @@ impl Cpu{
                 // 未実装2バイトNOP
                 self.program_counter += 1;
             },
+            0x0B | 0x2B => // ANC ※拡張命令
+            {
+                self.op_anc(memory_map);
+                self.program_counter += 2;
+            },
+            0x4B => // ALR ※拡張命令
+            {
+                self.op_alr(memory_map);
+                self.program_counter += 2;
+            },
+            0x6B => // ARR ※拡張命令
+            {
+                self.op_arr(memory_map);
+                self.program_counter += 2;
+            },
+            0x8B => // XAA ※拡張命令
+            {
+                self.op_xaa(memory_map);
+                self.program_counter += 2;
+            },
+            0xAB => // LAX #imm ※拡張命令
+            {
+                self.op_lax_immediate(memory_map);
+                self.program_counter += 2;
+            },
+            0xCB => // AXS ※拡張命令
+            {
+                self.op_axs(memory_map);
+                self.program_counter += 2;
+            },
+            0x93 => // SHA ※拡張命令
+            {
+                self.op_sh(&Addressing::Indirect_Y, self.reg_a & self.reg_x, memory_map);
+                self.program_counter += 2;
+            },
+            0x9F => // SHA ※拡張命令
+            {
+                self.op_sh(&Addressing::AbsoluteY, self.reg_a & self.reg_x, memory_map);
+                self.program_counter += 3;
+            },
+            0x9B => // TAS ※拡張命令
+            {
+                self.reg_s = self.reg_a & self.reg_x;
+                self.op_sh(&Addressing::AbsoluteY, self.reg_s, memory_map);
+                self.program_counter += 3;
+            },
+            0x9C => // SHY ※拡張命令
+            {
+                self.op_sh(&Addressing::AbsoluteX, self.reg_y, memory_map);
+                self.program_counter += 3;
+            },
+            0x9E => // SHX ※拡張命令
+            {
+                self.op_sh(&Addressing::AbsoluteY, self.reg_x, memory_map);
+                self.program_counter += 3;
+            },
+            0xBB => // LAS ※拡張命令
+            {
+                self.op_las(&Addressing::AbsoluteY, memory_map);
+                self.program_counter += 3;
+            },
+            0x02 | 0x12 | 0x22 | 0x32 | 0x42 | 0x52 |
+            0x62 | 0x72 | 0x92 | 0xB2 | 0xD2 | 0xF2 => // KIL ※拡張命令
+            {
+                // PCは進めずにCPUを停止する
+                self.halted = true;
+            },
             0x0C |
             0x1C |
             0x3C |
@@ -1822,16 +2066,91 @@ impl Cpu{
             0x74 |
             0xD4 |
             0xF4 |
-            0x80 =>
+            0x80 |
+            0x82 |
+            0x89 |
+            0xC2 |
+            0xE2 =>
             {
                 // 未実装2バイトNOP
                 self.program_counter += 2;
             },
-            _ =>
-            {
-                // System.out.println(Integer.toHexString(opcode & 0xFF));
-            }
         }
+        cycles
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.write_u32(self.program_counter);
+        w.write_u8(self.reg_a);
+        w.write_u8(self.reg_x);
+        w.write_u8(self.reg_y);
+        w.write_u8(self.reg_s);
+        w.write_u8(self.reg_p);
+        w.write_u8(self.irq_line);
+        w.write_bool(self.nmi_pending);
+        w.write_bool(self.irq_inhibit);
+        w.write_bool(self.skip_interrupt_poll);
+        w.write_bool(self.pending_vector.is_some());
+        w.write_u32(self.pending_vector.unwrap_or(0));
+        w.write_bool(self.halted);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.program_counter = r.read_u32()? & 0xFFFF;
+        self.reg_a = r.read_u8()?;
+        self.reg_x = r.read_u8()?;
+        self.reg_y = r.read_u8()?;
+        self.reg_s = r.read_u8()?;
+        self.reg_p = r.read_u8()?;
+        self.irq_line = r.read_u8()?;
+        self.nmi_pending = r.read_bool()?;
+        self.irq_inhibit = r.read_bool()?;
+        self.skip_interrupt_poll = r.read_bool()?;
+        let has_vector = r.read_bool()?;
+        let vector = r.read_u32()?;
+        self.pending_vector = match (has_vector, vector) {
+            (false, _) => None,
+            (true, 0xFFFA) | (true, 0xFFFC) | (true, 0xFFFE) => Some(vector),
+            (true, _) => return Err(StateError::Corrupted)
+        };
+        self.halted = r.read_bool()?;
+        Ok(())
+    }
+}
+
+// 各命令の基本サイクル数(ページ跨ぎ・分岐成立による加算分を除く)
+const CYCLE_TABLE: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0x00
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 0x10
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 0x20
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 0x30
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 0x40
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 0x50
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 0x60
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 0x70
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 0x80
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 0x90
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 0xA0
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // 0xB0
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // 0xC0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 0xD0
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // 0xE0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 0xF0
+];
+
+// ページを跨ぐと1サイクル加算される読み込み命令のアドレッシング
+// ストア命令・リードモディファイライト命令は常に最大サイクル数なので対象外
+fn page_cross_addressing(opcode: u8) -> Option<Addressing> {
+    match opcode {
+        0x11 | 0x31 | 0x51 | 0x71 | 0xB1 | 0xD1 | 0xF1 | 0xB3 =>
+            Some(Addressing::Indirect_Y),
+        0x19 | 0x39 | 0x59 | 0x79 | 0xB9 | 0xD9 | 0xF9 | 0xBE | 0xBF | 0xBB =>
+            Some(Addressing::AbsoluteY),
+        0x1D | 0x3D | 0x5D | 0x7D | 0xBD | 0xDD | 0xFD | 0xBC |
+        0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC =>
+            Some(Addressing::AbsoluteX),
+        _ => None
     }
 }
 
@@ -1842,18 +2161,8 @@ fn push_stack(cpu: &mut Cpu, value: u8, memory_map: &mut MemoryMap){
     cpu.reg_s = cpu.reg_s.wrapping_sub(1) as u8;
 }
 
-pub fn make_nmi_interrupt(cpu: &mut Cpu, memory_map: &mut MemoryMap){
-    if cpu.get_flag_i(){
-        // 割り込み禁止中
-        return;
-    }
-
-    let upper = (cpu.program_counter >> 8) as u8;
-    push_stack(cpu, upper, memory_map);
-    let lower = (cpu.program_counter) as u8;
-    push_stack(cpu, lower, memory_map);
-    cpu.set_flag_i(true);
-    cpu.set_flag_b(false); // TODO: これ必要？
-    let next_program_counter = memory_map.get_from_address16(0xFFFA);
-    cpu.program_counter = next_program_counter as u32;
-}
\ No newline at end of file
+// NMIはIフラグに関係なく受け付けられる
+pub fn make_nmi_interrupt(cpu: &mut Cpu){
+    // 命令の区切りで割り込みシーケンスを実行する
+    cpu.nmi_pending = true;
+}
@@ -0,0 +1,72 @@
+use super::{Chr, Mapper, read_banked, write_banked};
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::rom::Mirroring;
+
+// マッパー3 CHR 8KB単位の切り替え PRGはNROMと同じ
+pub struct Cnrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring,
+    chr_bank: u8
+}
+
+impl Cnrom {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr, prg_ram: Vec<u8>, mirroring: Mirroring) -> Cnrom {
+        Cnrom{prg_rom, chr, prg_ram, mirroring, chr_bank: 0}
+    }
+}
+
+impl Mapper for Cnrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        if address < 0x8000 {
+            return read_banked(&self.prg_ram, 0x2000, 0, (address - 0x6000) as usize);
+        }
+        read_banked(&self.prg_rom, 0x8000, 0, (address - 0x8000) as usize)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.chr_bank = value;
+        }
+        else if address >= 0x6000 {
+            write_banked(&mut self.prg_ram, 0x2000, 0, (address - 0x6000) as usize, value);
+        }
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr.read(0x2000, self.chr_bank as usize, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.chr.write(0x2000, self.chr_bank as usize, address as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        self.chr.save_state(w);
+        w.write_u8(self.chr_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        self.chr.load_state(r)?;
+        self.chr_bank = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use super::{Chr, Mapper, read_banked, write_banked};
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::rom::Mirroring;
+
+// マッパー1 シリアル書き込みの5bitシフトレジスタ経由で各レジスタを設定する
+pub struct Mmc1 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: Vec<u8>,
+    shift_register: u8,
+    shift_count: u8,
+    control: u8, // bit0-1: ミラーリング bit2-3: PRGバンクモード bit4: CHRバンクモード
+    chr_bank0: u8,
+    chr_bank1: u8,
+    prg_bank: u8 // bit0-3: PRGバンク bit4: PRG-RAM無効
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr, prg_ram: Vec<u8>) -> Mmc1 {
+        Mmc1{
+            prg_rom,
+            chr,
+            prg_ram,
+            shift_register: 0,
+            shift_count: 0,
+            control: 0x0C, // 起動時は$C000-$FFFFが最終バンク固定
+            chr_bank0: 0,
+            chr_bank1: 0,
+            prg_bank: 0
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank0 = value,
+            0xC000..=0xDFFF => self.chr_bank1 = value,
+            _ => self.prg_bank = value
+        }
+    }
+
+    // 512KB PRGのカートリッジ(SUROM)ではCHRバンクレジスタのbit4で256KB単位の外側のバンクを選ぶ
+    fn prg_outer_bank(&self) -> usize {
+        if self.prg_rom.len() > 0x40000 {
+            return (self.chr_bank0 & 0x10) as usize;
+        }
+        0
+    }
+
+    // 4KB単位のCHRバンク番号
+    fn chr_bank(&self, address: u16) -> usize {
+        if (self.control & 0x10) == 0 {
+            // 8KB単位で切り替え
+            return (self.chr_bank0 & 0x1E) as usize + (address >> 12) as usize;
+        }
+        let bank = if address < 0x1000 {self.chr_bank0} else {self.chr_bank1};
+        bank as usize
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        if address < 0x8000 {
+            if (self.prg_bank & 0x10) > 0 {
+                return 0;
+            }
+            return read_banked(&self.prg_ram, 0x2000, 0, (address - 0x6000) as usize);
+        }
+        let bank = (self.prg_bank & 0x0F) as usize;
+        let outer = self.prg_outer_bank();
+        let offset = (address & 0x3FFF) as usize;
+        let bank = match (self.control >> 2) & 0x03 {
+            0 | 1 => {
+                // 32KB単位で切り替え
+                (bank & 0x0E) + if address < 0xC000 {0} else {1}
+            },
+            2 => {
+                // $8000-$BFFFは最初のバンク固定
+                if address < 0xC000 {0} else {bank}
+            },
+            _ => {
+                // $C000-$FFFFは最終バンク固定
+                if address < 0xC000 {bank} else {0x0F}
+            }
+        };
+        read_banked(&self.prg_rom, 0x4000, outer + bank, offset)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address < 0x6000 {
+            return;
+        }
+        if address < 0x8000 {
+            if (self.prg_bank & 0x10) == 0 {
+                write_banked(&mut self.prg_ram, 0x2000, 0, (address - 0x6000) as usize, value);
+            }
+            return;
+        }
+        if (value & 0x80) > 0 {
+            // bit7が立っていればシフトレジスタをリセット
+            self.shift_register = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+        self.shift_register |= (value & 0x01) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count == 5 {
+            // 5回目の書き込みのアドレスで書き込み先のレジスタが決まる
+            let register_value = self.shift_register;
+            self.write_register(address, register_value);
+            self.shift_register = 0;
+            self.shift_count = 0;
+        }
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr.read(0x1000, self.chr_bank(address), (address & 0x0FFF) as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let bank = self.chr_bank(address);
+        self.chr.write(0x1000, bank, (address & 0x0FFF) as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x03 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal
+        }
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        self.chr.save_state(w);
+        w.write_u8(self.shift_register);
+        w.write_u8(self.shift_count);
+        w.write_u8(self.control);
+        w.write_u8(self.chr_bank0);
+        w.write_u8(self.chr_bank1);
+        w.write_u8(self.prg_bank);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        self.chr.load_state(r)?;
+        self.shift_register = r.read_u8()?;
+        self.shift_count = r.read_u8()?;
+        if self.shift_count > 4 {
+            return Err(StateError::Corrupted);
+        }
+        self.control = r.read_u8()?;
+        self.chr_bank0 = r.read_u8()?;
+        self.chr_bank1 = r.read_u8()?;
+        self.prg_bank = r.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+use super::{Chr, Mapper, read_banked, write_banked};
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::rom::Mirroring;
+
+// A12の立ち上がりを数えるのに必要なLowの期間(CPUサイクル)
+const A12_FILTER_CYCLES: u8 = 3;
+
+// マッパー4 8KB単位のPRGと1KB/2KB単位のCHRの切り替え、PPU A12の立ち上がりで数えるスキャンラインIRQ
+pub struct Mmc3 {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: Vec<u8>,
+    four_screen: bool,
+    bank_select: u8, // bit0-2: 次に書き込むバンクレジスタ bit6: PRGバンクモード bit7: CHR A12反転
+    bank_registers: [u8; 8], // R0-R5: CHRバンク R6-R7: PRGバンク
+    mirroring: Mirroring,
+    prg_ram_protect: u8, // bit6: 書き込み禁止 bit7: PRG-RAM有効
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+    a12: bool,
+    a12_low_cycles: u8
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr, prg_ram: Vec<u8>, mirroring: Mirroring) -> Mmc3 {
+        Mmc3{
+            prg_rom,
+            chr,
+            prg_ram,
+            four_screen: mirroring == Mirroring::FourScreen,
+            bank_select: 0,
+            bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            prg_ram_protect: 0x80,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+            a12: false,
+            a12_low_cycles: 0
+        }
+    }
+
+    fn prg_bank(&self, address: u16) -> usize {
+        let second_last = (self.prg_rom.len() / 0x2000).saturating_sub(2);
+        let swap = (self.bank_select & 0x40) > 0;
+        match (address >> 13) & 0x03 {
+            0 => if swap {second_last} else {self.bank_registers[6] as usize},
+            1 => self.bank_registers[7] as usize,
+            2 => if swap {self.bank_registers[6] as usize} else {second_last},
+            _ => second_last + 1
+        }
+    }
+
+    fn chr_bank(&self, address: u16) -> usize {
+        // bit7が立っていると2KBバンクと1KBバンクの配置が入れ替わる
+        let address = if (self.bank_select & 0x80) > 0 {address ^ 0x1000} else {address};
+        let slot = (address >> 10) as usize;
+        match slot {
+            0 | 1 => (self.bank_registers[0] & 0xFE) as usize + slot,
+            2 | 3 => (self.bank_registers[1] & 0xFE) as usize + slot - 2,
+            _ => self.bank_registers[slot - 2] as usize
+        }
+    }
+
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        if address < 0x8000 {
+            if (self.prg_ram_protect & 0x80) == 0 {
+                return 0;
+            }
+            return read_banked(&self.prg_ram, 0x2000, 0, (address - 0x6000) as usize);
+        }
+        read_banked(&self.prg_rom, 0x2000, self.prg_bank(address), (address & 0x1FFF) as usize)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if address < 0x6000 {
+            return;
+        }
+        if address < 0x8000 {
+            if (self.prg_ram_protect & 0xC0) == 0x80 {
+                write_banked(&mut self.prg_ram, 0x2000, 0, (address - 0x6000) as usize, value);
+            }
+            return;
+        }
+        // 各レジスタは8KBごとに偶数/奇数アドレスの組で並ぶ
+        match (address & 0xE000, address & 0x01) {
+            (0x8000, 0) => self.bank_select = value,
+            (0x8000, _) => self.bank_registers[(self.bank_select & 0x07) as usize] = value,
+            (0xA000, 0) => {
+                if !self.four_screen {
+                    self.mirroring = if (value & 0x01) > 0 {Mirroring::Horizontal} else {Mirroring::Vertical};
+                }
+            },
+            (0xA000, _) => self.prg_ram_protect = value,
+            (0xC000, 0) => self.irq_latch = value,
+            (0xC000, _) => {
+                // 次のA12の立ち上がりでラッチの値が再読み込みされる
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            },
+            (0xE000, 0) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            },
+            _ => self.irq_enabled = true
+        }
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr.read(0x0400, self.chr_bank(address), (address & 0x03FF) as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        let bank = self.chr_bank(address);
+        self.chr.write(0x0400, bank, (address & 0x03FF) as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn cpu_clock(&mut self) {
+        if !self.a12 {
+            self.a12_low_cycles = self.a12_low_cycles.saturating_add(1);
+        }
+    }
+
+    // 一定期間Lowだった後のA12の立ち上がりでカウンタを進める
+    // スプライトのフェッチ中の短いLowは無視される
+    fn ppu_address(&mut self, address: u16) {
+        let a12 = (address & 0x1000) > 0;
+        if a12 && !self.a12 && self.a12_low_cycles >= A12_FILTER_CYCLES {
+            self.clock_irq_counter();
+        }
+        if !a12 && self.a12 {
+            self.a12_low_cycles = 0;
+        }
+        self.a12 = a12;
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        self.chr.save_state(w);
+        w.write_u8(self.bank_select);
+        w.write_bytes(&self.bank_registers);
+        w.write_bool(self.mirroring == Mirroring::Horizontal);
+        w.write_u8(self.prg_ram_protect);
+        w.write_u8(self.irq_latch);
+        w.write_u8(self.irq_counter);
+        w.write_bool(self.irq_reload);
+        w.write_bool(self.irq_enabled);
+        w.write_bool(self.irq_pending);
+        w.write_bool(self.a12);
+        w.write_u8(self.a12_low_cycles);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        self.chr.load_state(r)?;
+        self.bank_select = r.read_u8()?;
+        r.read_bytes_into(&mut self.bank_registers)?;
+        let horizontal = r.read_bool()?;
+        if !self.four_screen {
+            self.mirroring = if horizontal {Mirroring::Horizontal} else {Mirroring::Vertical};
+        }
+        self.prg_ram_protect = r.read_u8()?;
+        self.irq_latch = r.read_u8()?;
+        self.irq_counter = r.read_u8()?;
+        self.irq_reload = r.read_bool()?;
+        self.irq_enabled = r.read_bool()?;
+        self.irq_pending = r.read_bool()?;
+        self.a12 = r.read_bool()?;
+        self.a12_low_cycles = r.read_u8()?;
+        Ok(())
+    }
+}
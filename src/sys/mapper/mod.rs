@@ -0,0 +1,105 @@
+use super::rom::Mirroring;
+use super::state::{StateError, StateReader, StateWriter};
+
+pub mod nrom;
+pub mod mmc1;
+pub mod uxrom;
+pub mod cnrom;
+pub mod mmc3;
+
+// カートリッジ上のバンク切り替え回路
+// CPU側は$4020-$FFFF、PPU側はパターンテーブル$0000-$1FFFのアクセスを受け持つ
+pub trait Mapper {
+    fn cpu_read(&mut self, address: u16) -> u8;
+    fn cpu_write(&mut self, address: u16, value: u8);
+    fn ppu_read(&self, address: u16) -> u8;
+    fn ppu_write(&mut self, address: u16, value: u8);
+    fn mirroring(&self) -> Mirroring;
+    // $6000-$7FFFのPRG-RAM バッテリーバックアップのセーブデータの入出力に使う
+    fn prg_ram(&self) -> &[u8];
+    fn prg_ram_mut(&mut self) -> &mut [u8];
+    fn irq(&self) -> bool {
+        false
+    }
+    // CPU 1サイクルごとに呼ばれる
+    fn cpu_clock(&mut self) {
+    }
+    // 描画中にPPUがパターンテーブルを読み込んだアドレスの通知 A12の監視に使う
+    fn ppu_address(&mut self, _address: u16) {
+    }
+    // バンクレジスタとカートリッジ上のRAMをセーブステートに入出力する
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+// iNESのマッパー番号から対応するマッパーを生成する 未対応ならNone
+pub fn create_mapper(mapper_number: u16, prg_rom: Vec<u8>, chr: Chr, prg_ram_size: usize, mirroring: Mirroring) -> Option<Box<dyn Mapper>> {
+    let prg_ram = vec![0; prg_ram_size];
+    let mapper: Box<dyn Mapper> = match mapper_number {
+        0 => Box::new(nrom::Nrom::new(prg_rom, chr, prg_ram, mirroring)),
+        1 => Box::new(mmc1::Mmc1::new(prg_rom, chr, prg_ram)),
+        2 => Box::new(uxrom::Uxrom::new(prg_rom, chr, prg_ram, mirroring)),
+        3 => Box::new(cnrom::Cnrom::new(prg_rom, chr, prg_ram, mirroring)),
+        4 => Box::new(mmc3::Mmc3::new(prg_rom, chr, prg_ram, mirroring)),
+        _ => return None
+    };
+    Some(mapper)
+}
+
+// バンク番号とバンク内オフセットからデータを読む バンク番号はバンク数で折り返す
+pub fn read_banked(data: &[u8], bank_size: usize, bank: usize, offset: usize) -> u8 {
+    if data.is_empty() {
+        return 0;
+    }
+    let bank_count = std::cmp::max(data.len() / bank_size, 1);
+    data[((bank % bank_count) * bank_size + offset) % data.len()]
+}
+
+pub fn write_banked(data: &mut [u8], bank_size: usize, bank: usize, offset: usize, value: u8) {
+    if data.is_empty() {
+        return;
+    }
+    let bank_count = std::cmp::max(data.len() / bank_size, 1);
+    let index = ((bank % bank_count) * bank_size + offset) % data.len();
+    data[index] = value;
+}
+
+// パターンテーブルのメモリ CHR ROMが無いカートリッジは書き込み可能なCHR RAMを持つ
+pub struct Chr {
+    data: Vec<u8>,
+    writable: bool
+}
+
+impl Chr {
+    pub fn rom(data: Vec<u8>) -> Chr {
+        Chr{data, writable: false}
+    }
+
+    pub fn ram(size: usize) -> Chr {
+        Chr{data: vec![0; size], writable: true}
+    }
+
+    pub fn read(&self, bank_size: usize, bank: usize, offset: usize) -> u8 {
+        read_banked(&self.data, bank_size, bank, offset)
+    }
+
+    pub fn write(&mut self, bank_size: usize, bank: usize, offset: usize, value: u8) {
+        if self.writable {
+            write_banked(&mut self.data, bank_size, bank, offset, value);
+        }
+    }
+
+    // ROMの内容はカートリッジから復元できるのでCHR RAMの場合だけ入出力する
+    pub fn save_state(&self, w: &mut StateWriter) {
+        if self.writable {
+            w.write_bytes(&self.data);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        if self.writable {
+            r.read_bytes_into(&mut self.data)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+use super::{Chr, Mapper, read_banked, write_banked};
+use super::super::state::{StateError, StateReader, StateWriter};
+use super::super::rom::Mirroring;
+
+// マッパー0 バンク切り替えなし PRG 16KBの場合は$C000-$FFFFにミラー
+pub struct Nrom {
+    prg_rom: Vec<u8>,
+    chr: Chr,
+    prg_ram: Vec<u8>,
+    mirroring: Mirroring
+}
+
+impl Nrom {
+    pub fn new(prg_rom: Vec<u8>, chr: Chr, prg_ram: Vec<u8>, mirroring: Mirroring) -> Nrom {
+        Nrom{prg_rom, chr, prg_ram, mirroring}
+    }
+}
+
+impl Mapper for Nrom {
+    fn cpu_read(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        }
+        if address < 0x8000 {
+            return read_banked(&self.prg_ram, 0x2000, 0, (address - 0x6000) as usize);
+        }
+        read_banked(&self.prg_rom, 0x8000, 0, (address - 0x8000) as usize)
+    }
+
+    fn cpu_write(&mut self, address: u16, value: u8) {
+        if (0x6000..0x8000).contains(&address) {
+            write_banked(&mut self.prg_ram, 0x2000, 0, (address - 0x6000) as usize, value);
+        }
+    }
+
+    fn ppu_read(&self, address: u16) -> u8 {
+        self.chr.read(0x2000, 0, address as usize)
+    }
+
+    fn ppu_write(&mut self, address: u16, value: u8) {
+        self.chr.write(0x2000, 0, address as usize, value);
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_ram(&self) -> &[u8] {
+        &self.prg_ram
+    }
+
+    fn prg_ram_mut(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.prg_ram);
+        self.chr.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_bytes_into(&mut self.prg_ram)?;
+        self.chr.load_state(r)?;
+        Ok(())
+    }
+}
@@ -1,15 +1,19 @@
-use super::{cpu::{Cpu, make_nmi_interrupt}, ppu::Ppu, rom::Rom};
+use super::{apu::Apu, controller::Controller, cpu::{Cpu, make_nmi_interrupt}, ppu::Ppu, rom::Rom, state::{StateError, StateReader, StateWriter}};
 
 pub struct MemoryMap {
     pub rom: Rom,
     pub wram: Vec<u8>,
-    pub ppu: Ppu
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub controllers: [Controller; 2],
+    pub oam_dma_pending: bool // $4014に書き込まれ、CPUを停止させる必要がある
 }
 
 impl MemoryMap {
     pub fn new(rom: Rom, ppu: Ppu) -> MemoryMap {
         let wram = vec!(0; 0x800);
-        MemoryMap{rom, wram, ppu}
+        let controllers = [Controller::new(), Controller::new()];
+        MemoryMap{rom, wram, ppu, apu: Apu::new(), controllers, oam_dma_pending: false}
     }
 
     pub fn get_from_address(&mut self, address: u32) -> u8{
@@ -17,8 +21,9 @@ impl MemoryMap {
             //WRAM MIRROR * 3
             return self.wram[(address % 0x800) as usize];
         }
-        else if address < 0x2008 {
-            // ppu i/o
+        else if address < 0x4000 {
+            // ppu i/o ($2008-$3FFFはミラー)
+            let address = 0x2000 + (address & 0x07);
             if address == 0x2000 {
                 return self.ppu.ppu_reg[0];
             }
@@ -26,7 +31,7 @@ impl MemoryMap {
                 return self.ppu.ppu_reg[1];
             }
             else if address == 0x2002 {
-                return self.ppu.ppu_reg[2];
+                return self.ppu.read_ppu_status();
             }
             else if address == 0x2004 {
                 return self.ppu.read_oam_data();
@@ -37,36 +42,24 @@ impl MemoryMap {
             else if address == 0x2006 {
             }
             else if address == 0x2007 {
-                return self.ppu.read_ppu_data();
+                return self.ppu.read_ppu_data(&self.rom);
             }
         }
-        else if address < 0x4000 {
-            // ppu i/o mirror * 1023
-        }
         else if address < 0x4020 {
             // apu i/o, pad
-            if address == 0x4016 {
-                let value:u8 = 0;
-                // value |= joyPad.buttonReadFromIO() ? 0x01 : 0x00;
-                return value;
+            if address == 0x4015 {
+                return self.apu.read_status();
+            }
+            else if address == 0x4016 {
+                return self.controllers[0].read();
+            }
+            else if address == 0x4017 {
+                return self.controllers[1].read();
             }
-        }
-        else if address < 0x6000 {
-            // exrom
-        }
-        else if address < 0x8000 {
-            // exram
-        }
-        else if address < 0xC000 {
-            // prg-rom low
-            return self.rom.prg_rom[(address as usize - 0x8000) % self.rom.prg_rom.len()];
         }
         else if address <= 0xFFFF {
-            // prg-ram high
-            return self.rom.prg_rom[(address as usize - 0x8000) % self.rom.prg_rom.len()];
-        }
-        else{
-            // ppu
+            // exrom, exram, prg-rom
+            return self.rom.mapper.cpu_read(address as u16);
         }
         return 0x00;
     }
@@ -75,17 +68,28 @@ impl MemoryMap {
         if 0x0000 <= address && address < 0x2000 {
             //WRAM MIRROR * 3
             self.wram[(address % 0x800) as usize] = value;
-        } else if address < 0x2008 {
-            // ppu i/o
+        } else if address < 0x4000 {
+            // ppu i/o ($2008-$3FFFはミラー)
+            let address = 0x2000 + (address & 0x07);
+            if address == 0x2002 {
+                // PPUSTATUSは読み込み専用
+                return;
+            }
             self.ppu.ppu_reg[(address - 0x2000) as usize] = value;
-            if address == 0x2005 {
+            if address == 0x2000 {
+                self.ppu.write_ppu_ctrl();
+            }
+            else if address == 0x2004 {
+                self.ppu.write_oam_data(value);
+            }
+            else if address == 0x2005 {
                 self.ppu.write_ppu_scroll();
             }
             else if address == 0x2006 {
                 self.ppu.write_ppu_addr();
             }
             else if address == 0x2007 {
-                self.ppu.write_ppu_data();
+                self.ppu.write_ppu_data(&mut self.rom);
             }
             // else if( address == 0x2003 ){
             //     final int a = 1;
@@ -96,10 +100,22 @@ impl MemoryMap {
             // else if( address == 0x2005 ){
             //     final int a = 1;
             // }
+        } else if (0x4000..=0x4013).contains(&address) || address == 0x4015 || address == 0x4017 {
+            self.apu.write_register(address as u16, value);
         } else if address == 0x4014 {
-            self.ppu.sprite_dma(value, &self.wram);
+            // 指定したページの256バイトをCPUのバス経由で読み込む
+            let mut data = [0; 0x100];
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = self.get_from_address(((value as u32) << 8) | i as u32);
+            }
+            self.ppu.sprite_dma(&data);
+            self.oam_dma_pending = true;
         } else if address == 0x4016 {
-            // joyPad.buttonResetFromIO();
+            // ストローブは両方のコントローラに接続されている
+            self.controllers[0].write_strobe(value);
+            self.controllers[1].write_strobe(value);
+        } else if (0x4020..=0xFFFF).contains(&address) {
+            self.rom.mapper.cpu_write(address as u16, value);
         }
     }
 
@@ -127,20 +143,31 @@ impl MemoryMap {
     }
 
     pub fn ppu_next_cycle(&mut self, frame_buffer: &mut [u8], cpu: &mut Cpu){
-        self.ppu.next_cycle(frame_buffer, &self.rom);
-        if self.ppu.current_line == 241 {
-            // VBLANKフラグ=1
-            self.ppu.ppu_reg[0] |= 0x80;
+        self.ppu.next_cycle(frame_buffer, &mut self.rom);
+        if self.ppu.poll_nmi() {
             // nmi割り込み
-            make_nmi_interrupt(cpu, self)
+            make_nmi_interrupt(cpu);
         }
-        if self.ppu.current_line == 261 {
-            // VBLANKフラグ=0
-            self.ppu.ppu_reg[0] &= 0x7F;
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.write_bytes(&self.wram);
+        self.ppu.save_state(w);
+        self.apu.save_state(w);
+        for controller in self.controllers.iter() {
+            controller.save_state(w);
         }
-        if self.ppu.current_line == 341 {
-            self.ppu.current_line = 0;
+        self.rom.mapper.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        r.read_bytes_into(&mut self.wram)?;
+        self.ppu.load_state(r)?;
+        self.apu.load_state(r)?;
+        for controller in self.controllers.iter_mut() {
+            controller.load_state(r)?;
         }
+        self.rom.mapper.load_state(r)
     }
 }
 
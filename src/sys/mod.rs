@@ -2,4 +2,10 @@ pub mod rom;
 pub mod system;
 pub mod memory_map;
 pub mod ppu;
-pub mod cpu;
\ No newline at end of file
+pub mod cpu;
+pub mod controller;
+pub mod apu;
+pub mod audio;
+pub mod mapper;
+pub mod state;
+pub mod rewind;
\ No newline at end of file
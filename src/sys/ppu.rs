@@ -1,17 +1,32 @@
-use super::{rom::Rom};
+use super::{rom::{Mirroring, Rom}, state::{StateError, StateReader, StateWriter}};
 
 pub struct Ppu {
-    pub ppu_ram: [u8; 0x4000], // TODO: 今は容量適当
+    pub vram: [u8; 0x1000], // ネームテーブル 本体は2KB 4画面はカートリッジ側の2KBを含む
+    pub palette_ram: [u8; 0x20],
+    pub read_buffer: u8, // $2007読み込みの1バイト遅延用バッファ
     pub ppu_oam: [u8; 0x100],
     pub ppu_reg: [u8; 8],
-    pub ppu_addr_count: u8, //TODO: 直せたら直す
-    pub ppu_addr: u16,
-    pub ppu_scroll_count: u8, //TODO: 直せたら直す
-    pub scroll_x: u8,
-    pub scroll_y: u8,
-    pub attribute_table_cache: [u8; 16*16], // 各16x16pixelの画面領域で使うパレット
-    pub current_line: u16
-    // TODO: PPURAMWrite作る ミラー領域とかの考慮のため
+    // PPU内部レジスタ
+    // vram_addr/tmp_vram_addr: yyy NN YYYYY XXXXX (fine Y, ネームテーブル, coarse Y, coarse X)
+    pub vram_addr: u16,
+    pub tmp_vram_addr: u16,
+    pub fine_x: u8,
+    pub write_toggle: bool, // $2005/$2006で共有される書き込みラッチ
+    // BGのシフトレジスタ 8ドットごとに読み込んだタイルを下位8bitに補充し、1ドットごとに左へシフトする
+    pub bg_pattern_shift: [u16; 2], // パターンの下位/上位プレーン
+    pub bg_palette_shift: [u16; 2], // 属性テーブルのパレット番号の下位/上位bitを8ドット分に広げたもの
+    pub bg_next_tile: [u8; 3], // 次に補充するタイル (パターン下位, パターン上位, パレット番号)
+    pub current_line: u16,
+    pub current_dot: u16,
+    pub nmi_output: bool, // 前のドットでのNMI出力(エッジ検出用)
+    pub nmi_delay: u8,
+    pub suppress_vblank: bool,
+    // 描画中のラインのX座標ごとのスプライトのピクセル ライン開始時に求める
+    // bit0-1 ピクセル値(0は透明) bit2-3 パレット bit4 BGの背面 bit5 スプライト0
+    pub sprite_line: [u8; 256],
+    pub sprite_fetch_addrs: [u16; 8], // ドット257-320で読み込む次のラインのスプライトのパターンアドレス
+    pub odd_frame: bool,
+    pub frame_count: u64 // VBLANKに入るたびに増える
 }
 
 
@@ -53,260 +68,562 @@ const fn generate_color_palette() -> [u8; 64 * 3]{
 impl Ppu {
     pub fn new() -> Ppu {
         Ppu{
-            ppu_ram: [0; 0x4000],
+            vram: [0; 0x1000],
+            palette_ram: [0; 0x20],
+            read_buffer: 0,
             ppu_oam: [0; 0x100],
             ppu_reg: [0; 8],
-            ppu_addr_count: 0,
-            ppu_addr: 0,
-            ppu_scroll_count: 0,
-            scroll_x: 0,
-            scroll_y: 0,
-            attribute_table_cache: [0; 16*16],
-            current_line: 241
+            vram_addr: 0,
+            tmp_vram_addr: 0,
+            fine_x: 0,
+            write_toggle: false,
+            bg_pattern_shift: [0; 2],
+            bg_palette_shift: [0; 2],
+            bg_next_tile: [0; 3],
+            current_line: 261, // プリレンダーラインから始め、最初のフレームも画面全体を描画する
+            current_dot: 0,
+            nmi_output: false,
+            nmi_delay: 0,
+            suppress_vblank: false,
+            sprite_line: [0; 256],
+            sprite_fetch_addrs: [0; 8],
+            odd_frame: false,
+            frame_count: 0
         }
     }
 
+    pub fn read_ppu_status(&mut self) -> u8{
+        let status = self.ppu_reg[2];
+        // 読み出しでVBLANKフラグと書き込みラッチはクリアされる
+        self.ppu_reg[2] &= 0x7F;
+        self.write_toggle = false;
+        if self.current_line == 241 && self.current_dot == 0 {
+            // フラグがセットされる直前に読まれた場合はこのフレームのVBLANKとNMIが発生しない
+            self.suppress_vblank = true;
+        }
+        status
+    }
+
+    // NMI出力(VBLANKフラグ && $2000 bit7)の立ち上がりを検出し、CPUへNMIを通知すべきならtrue
+    // 立ち上がりから数ドット以内に$2002が読まれるとNMIは発生しない
+    pub fn poll_nmi(&mut self) -> bool{
+        const NMI_DELAY_DOTS: u8 = 3;
+        let output = (self.ppu_reg[2] & 0x80) > 0 && (self.ppu_reg[0] & 0x80) > 0;
+        if !output {
+            self.nmi_output = false;
+            self.nmi_delay = 0;
+            return false;
+        }
+        if !self.nmi_output {
+            self.nmi_output = true;
+            self.nmi_delay = NMI_DELAY_DOTS;
+        }
+        if self.nmi_delay > 0 {
+            self.nmi_delay -= 1;
+            return self.nmi_delay == 0;
+        }
+        false
+    }
+
+    pub fn write_ppu_ctrl(&mut self){
+        // ネームテーブル選択はtのbit10-11
+        self.tmp_vram_addr = (self.tmp_vram_addr & 0xF3FF) | (((self.ppu_reg[0] & 0x03) as u16) << 10);
+    }
+
     pub fn write_ppu_addr(&mut self){
-        if self.ppu_addr_count == 0 {
-            self.ppu_addr = ((self.ppu_reg[6] & 0xFF) as u16) << 8;
-        } else if self.ppu_addr_count == 1 {
-            self.ppu_addr = (self.ppu_addr | (self.ppu_reg[6] & 0xFF) as u16) as u16
+        let value = self.ppu_reg[6] as u16;
+        if !self.write_toggle {
+            self.tmp_vram_addr = (self.tmp_vram_addr & 0x00FF) | ((value & 0x3F) << 8);
+        } else {
+            self.tmp_vram_addr = (self.tmp_vram_addr & 0xFF00) | value;
+            self.vram_addr = self.tmp_vram_addr;
         }
-        self.ppu_addr_count = (self.ppu_addr_count + 1) % 2;
+        self.write_toggle = !self.write_toggle;
     }
 
     pub fn write_ppu_scroll(&mut self){
-        if self.ppu_scroll_count == 0 {
-            self.scroll_x = self.ppu_reg[5];
-        } else if self.ppu_scroll_count == 1 {
-            self.scroll_y = self.ppu_reg[5];
+        let value = self.ppu_reg[5] as u16;
+        if !self.write_toggle {
+            self.tmp_vram_addr = (self.tmp_vram_addr & 0xFFE0) | (value >> 3);
+            self.fine_x = (value & 0x07) as u8;
+        } else {
+            self.tmp_vram_addr = (self.tmp_vram_addr & 0x0C1F) | ((value & 0x07) << 12) | ((value >> 3) << 5);
         }
-        self.ppu_scroll_count = (self.ppu_scroll_count + 1) % 2;
+        self.write_toggle = !self.write_toggle;
+    }
+
+    fn is_rendering_enabled(&self) -> bool{
+        (self.ppu_reg[1] & 0x18) > 0
+    }
+
+    fn is_rendering_line(&self) -> bool{
+        self.current_line < 240 || self.current_line == 261
     }
 
-    pub fn read_ppu_data(&mut self) -> u8{
-        let ret_data = self.ppu_ram[self.ppu_addr as usize];
-        let mut address_inc = 1;
-        if (self.ppu_reg[0] & 0x04) > 0 { // $2000の値によって32byteインクリメント
-            address_inc = 32;
+    fn increment_coarse_x(&mut self){
+        if (self.vram_addr & 0x001F) == 31 {
+            self.vram_addr &= !0x001F;
+            self.vram_addr ^= 0x0400; // 水平方向のネームテーブル切り替え
+        } else {
+            self.vram_addr += 1;
         }
-        self.ppu_addr += address_inc;
-        return ret_data;
     }
 
-    pub fn read_oam_data(&mut self) -> u8{
-        let ret_data = self.ppu_oam[self.ppu_reg[3] as usize];
-        self.ppu_reg[3] = self.ppu_reg[3].wrapping_add(1);
-        return ret_data;
+    fn increment_y(&mut self){
+        if (self.vram_addr & 0x7000) != 0x7000 {
+            self.vram_addr += 0x1000;
+            return;
+        }
+        self.vram_addr &= !0x7000;
+        let mut coarse_y = (self.vram_addr & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.vram_addr ^= 0x0800; // 垂直方向のネームテーブル切り替え
+        } else if coarse_y == 31 {
+            // 属性テーブルの位置から溢れた場合はネームテーブルを切り替えない
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.vram_addr = (self.vram_addr & !0x03E0) | (coarse_y << 5);
     }
 
-    pub fn write_ppu_data(&mut self){
-        if self.ppu_addr == 0x3F00 || self.ppu_addr == 0x3F10 {
-            self.ppu_ram[0x3F00] = self.ppu_reg[7];
-            self.ppu_ram[0x3F10] = self.ppu_reg[7]; // mirror
+    // $2007アクセス後のアドレス更新
+    fn increment_vram_addr(&mut self){
+        if self.is_rendering_enabled() && self.is_rendering_line() {
+            // 描画中はcoarse XとYが同時にインクリメントされる
+            self.increment_coarse_x();
+            self.increment_y();
+            return;
         }
-        else if self.ppu_addr == 0x3F04 || self.ppu_addr == 0x3F14 {
-            self.ppu_ram[0x3F04] = self.ppu_reg[7];
-            self.ppu_ram[0x3F14] = self.ppu_reg[7]; // mirror
+        let address_inc = if (self.ppu_reg[0] & 0x04) > 0 {32} else {1}; // $2000の値によって32byteインクリメント
+        self.vram_addr = (self.vram_addr + address_inc) & 0x7FFF;
+    }
+
+    // ネームテーブル領域のアドレスをミラーリングを考慮したvramのインデックスに変換する
+    fn vram_index(address: u16, mirroring: Mirroring) -> usize{
+        let logical_screen = (address >> 10) & 0x03;
+        let physical_screen = match mirroring {
+            Mirroring::Horizontal => logical_screen >> 1,
+            Mirroring::Vertical => logical_screen & 0x01,
+            Mirroring::FourScreen => logical_screen,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1
+        };
+        (physical_screen * 0x400 + (address & 0x3FF)) as usize
+    }
+
+    // $3F10/$3F14/$3F18/$3F1Cは$3F00/$3F04/$3F08/$3F0Cのミラー
+    fn palette_index(address: u16) -> usize{
+        let index = address & 0x1F;
+        if (index & 0x13) == 0x10 {
+            return (index & 0x0F) as usize;
         }
-        else if self.ppu_addr == 0x3F08 || self.ppu_addr == 0x3F18 {
-            self.ppu_ram[0x3F08] = self.ppu_reg[7];
-            self.ppu_ram[0x3F18] = self.ppu_reg[7]; // mirror
+        index as usize
+    }
+
+    // PPUバス $0000-$1FFF パターンテーブル(カートリッジ) $2000-$3EFF ネームテーブル $3F00-$3FFF パレット
+    pub fn read_bus(&self, address: u16, rom: &Rom) -> u8{
+        let address = address & 0x3FFF;
+        if address < 0x2000 {
+            rom.mapper.ppu_read(address)
         }
-        else if self.ppu_addr == 0x3F0C || self.ppu_addr == 0x3F1C {
-            self.ppu_ram[0x3F0C] = self.ppu_reg[7];
-            self.ppu_ram[0x3F1C] = self.ppu_reg[7]; // mirror
+        else if address < 0x3F00 {
+            self.vram[Ppu::vram_index(address, rom.mapper.mirroring())]
         }
         else {
-            self.ppu_ram[self.ppu_addr as usize] = self.ppu_reg[7];
+            self.palette_ram[Ppu::palette_index(address)] & 0x3F
+        }
+    }
+
+    pub fn write_bus(&mut self, address: u16, value: u8, rom: &mut Rom){
+        let address = address & 0x3FFF;
+        if address < 0x2000 {
+            // CHR RAMのみ書き込める
+            rom.mapper.ppu_write(address, value);
         }
-        let mut address_inc = 1;
-        if (self.ppu_reg[0] & 0x04) > 0 { // $2000の値によって32byteインクリメント
-            address_inc = 32;
+        else if address < 0x3F00 {
+            self.vram[Ppu::vram_index(address, rom.mapper.mirroring())] = value;
+        }
+        else {
+            self.palette_ram[Ppu::palette_index(address)] = value;
         }
-        self.ppu_addr += address_inc;
     }
 
-    pub fn sprite_dma(&mut self, address_upper: u8, cpu_ram: &[u8]){
-        let start = (((address_upper & 0xFF) as u16) << 8) as usize;
-        self.ppu_oam[0..0x100].clone_from_slice(&cpu_ram[start..]);
+    pub fn read_ppu_data(&mut self, rom: &Rom) -> u8{
+        let address = self.vram_addr & 0x3FFF;
+        let ret_data = if address < 0x3F00 {
+            // パレット以外は前回読んだ値が返り、今回の値はバッファに入る
+            let buffered = self.read_buffer;
+            self.read_buffer = self.read_bus(address, rom);
+            buffered
+        } else {
+            // パレットは即座に返るが、バッファには下にあるネームテーブルの値が入る
+            self.read_buffer = self.read_bus(address - 0x1000, rom);
+            self.read_bus(address, rom)
+        };
+        self.increment_vram_addr();
+        return ret_data;
     }
 
-    pub fn refresh_attribute_table(&mut self){
-        let main_screen = self.ppu_reg[0] & 0x03;
-        let start_addr = 0x2000 + (main_screen as u16 * 0x400);
-        let end_addr = start_addr + 32 * 30;
-        let attribute_table_start_addr = start_addr + 0x3C0;
-        for attribute_table_addr in attribute_table_start_addr..end_addr{
-            let value = self.ppu_ram[attribute_table_addr as usize] & 0xFF;
-            let top_left = value & 0x03;
-            let top_right = (value & 0x0C) >> 2;
-            let bottom_left = (value & 0x30) >> 4;
-            let bottom_right = (value & 0xC0) >> 6;
-            let attribute_table_y = attribute_table_addr / 8;
-            let area_y = 2 * attribute_table_y;
-            let attribute_table_x = attribute_table_addr % 8;
-            let area_x = 2 * attribute_table_x;
-            self.attribute_table_cache[(area_y * 8 + area_x) as usize] = top_left;
-            self.attribute_table_cache[(area_y * 8 + area_x + 1) as usize] = top_right;
-            self.attribute_table_cache[((area_y + 1) * 8 + area_x) as usize] = bottom_left;
-            self.attribute_table_cache[((area_y + 1) * 8 + area_x + 1) as usize] = bottom_right;
-        }
+    pub fn read_oam_data(&mut self) -> u8{
+        let ret_data = self.ppu_oam[self.ppu_reg[3] as usize];
+        self.ppu_reg[3] = self.ppu_reg[3].wrapping_add(1);
+        return ret_data;
     }
 
+    pub fn write_ppu_data(&mut self, rom: &mut Rom){
+        self.write_bus(self.vram_addr, self.ppu_reg[7], rom);
+        self.increment_vram_addr();
+    }
 
-    pub fn next_cycle(&mut self, frame_buffer: &mut [u8], rom: &Rom){
-        self.refresh_attribute_table();
-        self.draw(frame_buffer, &rom.chr_rom);
-        self.current_line = self.current_line + 1;
+    // $2004への書き込み OAMADDRを1進める
+    pub fn write_oam_data(&mut self, value: u8){
+        self.ppu_oam[self.ppu_reg[3] as usize] = value;
+        self.ppu_reg[3] = self.ppu_reg[3].wrapping_add(1);
+    }
+
+    // $4014のDMA 256バイトを$2004へ順に書き込むのと同じでOAMADDRから始まる
+    pub fn sprite_dma(&mut self, data: &[u8; 0x100]){
+        for value in data.iter() {
+            self.write_oam_data(*value);
+        }
+    }
+
+    // 1ドット進める 1ライン341ドット、1フレーム262ライン
+    pub fn next_cycle(&mut self, frame_buffer: &mut [u8], rom: &mut Rom){
+        self.current_dot += 1;
+        if self.current_line == 261 && self.current_dot == 340 && self.odd_frame && self.is_rendering_enabled() {
+            // 奇数フレームで描画が有効ならプリレンダーラインの最後の1ドットを飛ばす
+            self.current_dot = 341;
+        }
+        if self.current_dot == 341 {
+            self.current_dot = 0;
+            self.current_line = (self.current_line + 1) % 262;
+            if self.current_line == 0 {
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+        if self.current_line < 240 && self.current_dot == 1 {
+            self.prepare_sprite_line(rom);
+        }
+        if self.is_rendering_enabled() && self.is_rendering_line() {
+            self.notify_pattern_fetch(rom);
+            self.clock_bg(rom);
+            match self.current_dot {
+                256 => self.increment_y(),
+                257 => {
+                    // tの水平方向の成分をvへコピー
+                    self.vram_addr = (self.vram_addr & !0x041F) | (self.tmp_vram_addr & 0x041F);
+                },
+                280..=304 if self.current_line == 261 => {
+                    // tの垂直方向の成分をvへコピー
+                    self.vram_addr = (self.vram_addr & !0x7BE0) | (self.tmp_vram_addr & 0x7BE0);
+                },
+                _ => {}
+            }
+        }
+        if self.current_line < 240 && (1..=256).contains(&self.current_dot) {
+            self.draw_dot(frame_buffer);
+        }
+        if self.current_dot == 1 {
+            if self.current_line == 241 {
+                // VBLANKフラグ=1
+                if !self.suppress_vblank {
+                    self.ppu_reg[2] |= 0x80;
+                }
+                self.suppress_vblank = false;
+                self.frame_count += 1;
+            }
+            else if self.current_line == 261 {
+                // VBLANK・スプライト0ヒット・スプライトオーバーフローフラグ=0
+                self.ppu_reg[2] &= 0x1F;
+            }
+        }
     }
     
     fn get_bg_color_id(&self, palette: i32, num: i32) -> u8{
         const PALETTE_TABLE_BG_ADDR: i32 = 0x3F00;
-        return self.ppu_ram[(PALETTE_TABLE_BG_ADDR + 4 * palette + num) as usize];
+        return self.palette_ram[Ppu::palette_index((PALETTE_TABLE_BG_ADDR + 4 * palette + num) as u16)];
     }
 
     fn get_sp_color_id(&self, palette: i32, num: i32) -> u8{
         const PALETTE_TABLE_SP_ADDR: i32 = 0x3F10;
-        return self.ppu_ram[(PALETTE_TABLE_SP_ADDR + 4 * palette + num) as usize];
-    }
-
-    pub fn draw(&self, frame_buffer: &mut [u8], chr_rom: &[u8]){
-        const BLOCK_WIDTH: i32 = 256 / 8;
-        let bg_offset_addr: u32 = if (self.ppu_reg[0] & 0x10) > 0 {0x1000} else {0};
-        let main_screen = self.ppu_reg[0] & 0x03;
-        let start_addr = 0x2000 + (main_screen as u16 * 0x400);
-        let end_addr = start_addr + 32 * 30;
-
-        // BG描画
-        for addr in start_addr..end_addr {
-            let tile_id = self.ppu_ram[addr as usize];
-            if tile_id > 0 {
-                let offset_block_x = (addr - start_addr) as i32 % BLOCK_WIDTH;
-                let offset_block_y = (addr - start_addr) as i32 / BLOCK_WIDTH;
-                let offset_x = offset_block_x * 8 - self.scroll_x as i32;
-                let offset_y = offset_block_y * 8 - self.scroll_y as i32;
-
-                // if self.current_line < offset_y as u16 || offset_y as u16 + 8 <= self.current_line {
-                //     break; // FIXME: 暫定処理
-                // }
-
-                let tile = build_tile(tile_id as u8, bg_offset_addr, &chr_rom);
-                let attX = offset_x % 16;
-                let attY = offset_y / 16;
-                let palette = self.attribute_table_cache[(attX * 16 + attY) as usize];
-
-                if 0 <= offset_x && 0 <= offset_y {
-                    self.draw_bg(palette, &tile, offset_x as u8, offset_y as u8, frame_buffer);
-                }
+        return self.palette_ram[Ppu::palette_index((PALETTE_TABLE_SP_ADDR + 4 * palette + num) as u16)];
+    }
+
+    // BGのシフトレジスタを1ドット分進める
+    // ドット8ごとに現在のvからタイルを読み込んでcoarse Xを進め、次のドットでシフトレジスタに補充する
+    // ドット321-336では次のラインの先頭2タイルを先読みする
+    fn clock_bg(&mut self, rom: &Rom){
+        let dot = self.current_dot;
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            for i in 0..2 {
+                self.bg_pattern_shift[i] <<= 1;
+                self.bg_palette_shift[i] <<= 1;
+            }
+            if (dot - 1).is_multiple_of(8) {
+                self.reload_bg_shift();
             }
         }
+        if ((1..=256).contains(&dot) || (321..=336).contains(&dot)) && dot.is_multiple_of(8) {
+            self.fetch_bg_tile(rom);
+            self.increment_coarse_x();
+        }
+    }
 
+    fn fetch_bg_tile(&mut self, rom: &Rom){
+        let vram_addr = self.vram_addr;
+        let tile_id = self.read_bus(0x2000 | (vram_addr & 0x0FFF), rom);
+        let attribute_addr = 0x23C0 | (vram_addr & 0x0C00) | ((vram_addr >> 4) & 0x38) | ((vram_addr >> 2) & 0x07);
+        let attribute = self.read_bus(attribute_addr, rom);
+        let attribute_shift = ((vram_addr >> 4) & 0x04) | (vram_addr & 0x02);
+        let bg_offset_addr: u16 = if (self.ppu_reg[0] & 0x10) > 0 {0x1000} else {0};
+        let fine_y = (vram_addr >> 12) & 0x07;
+        let pattern_addr = bg_offset_addr + tile_id as u16 * 16 + fine_y;
+        self.bg_next_tile = [
+            self.read_bus(pattern_addr, rom),
+            self.read_bus(pattern_addr + 8, rom),
+            (attribute >> attribute_shift) & 0x03
+        ];
+    }
 
-        // Sprite描画
-        let offset_addr_glob: u32 = if (self.ppu_reg[0] & 0x08) > 0 {0x1000} else {0};
-        for sprite_addr in (0..0x100).step_by(4) {
-            let tile_y       = (self.ppu_oam[sprite_addr + 0] & 0xFF) as i32 - self.scroll_x as i32;
-            let tile_x       = (self.ppu_oam[sprite_addr + 3] & 0xFF) as i32 - self.scroll_y as i32;
-            if tile_y >= 240 {
-                break;
-            }
-            
-            let attr    = self.ppu_oam[sprite_addr + 2] & 0xFF;
-            let palette = attr & 0x03;
-            if (self.ppu_reg[0] & 0x20) > 0 { // スプライトサイズ8 * 16の場合
-                let tile_id_top = self.ppu_oam[sprite_addr + 1] & 0xFE;
-                let tile_id_bottom = (self.ppu_oam[sprite_addr + 1] & 0xFE) | 1;
-                
-                // レジスタ $2000 によるパターンテーブル選択を無視する
-                let pattern_table = self.ppu_oam[sprite_addr + 1] & 0x01;
-                let offset_addr = if pattern_table > 0 {0x1000} else {0};
-                
-                if tile_id_top > 0 {
-                    let tile = build_tile(tile_id_top, offset_addr, chr_rom);
-                    if 0 <= tile_x && 0 <= tile_y {
-                        self.draw_sprite(palette, &tile, tile_x as u8, tile_y as u8, frame_buffer);
-                    }
-                }
-                if tile_id_bottom > 0 {
-                    let tile = build_tile(tile_id_bottom, offset_addr, chr_rom);
-                    if 0 <= tile_x && 0 <= tile_y {
-                        self.draw_sprite(palette, &tile, tile_x as u8, tile_y as u8, frame_buffer);
-                    }
-                }
+    fn reload_bg_shift(&mut self){
+        let [lower, upper, palette] = self.bg_next_tile;
+        self.bg_pattern_shift[0] = (self.bg_pattern_shift[0] & 0xFF00) | lower as u16;
+        self.bg_pattern_shift[1] = (self.bg_pattern_shift[1] & 0xFF00) | upper as u16;
+        for i in 0..2 {
+            let bits = if (palette >> i) & 0x01 > 0 {0xFF} else {0x00};
+            self.bg_palette_shift[i] = (self.bg_palette_shift[i] & 0xFF00) | bits;
+        }
+    }
+
+    // ドット1-256でX=ドット-1の1ピクセルをBGのシフトレジスタとスプライトのラインから描画する
+    fn draw_dot(&mut self, frame_buffer: &mut [u8]){
+        let x = (self.current_dot - 1) as usize;
+        let show_bg = (self.ppu_reg[1] & 0x08) > 0 && (x >= 8 || (self.ppu_reg[1] & 0x02) > 0);
+        let show_sprite = (self.ppu_reg[1] & 0x10) > 0 && (x >= 8 || (self.ppu_reg[1] & 0x04) > 0);
 
+        let mut bg_pixel = 0;
+        let mut color_id = self.get_bg_color_id(0, 0);
+        if show_bg {
+            // fine Xはシフトレジスタから取り出すビットの位置
+            let shift = 15 - self.fine_x as u16;
+            let bit = |value: u16| ((value >> shift) & 0x01) as u8;
+            bg_pixel = (bit(self.bg_pattern_shift[1]) << 1) | bit(self.bg_pattern_shift[0]);
+            if bg_pixel > 0 {
+                let palette = (bit(self.bg_palette_shift[1]) << 1) | bit(self.bg_palette_shift[0]);
+                color_id = self.get_bg_color_id(palette.into(), bg_pixel.into());
             }
-            else {
-                let tile_id = self.ppu_oam[sprite_addr + 1] & 0xFF;
-                
-                if tile_id > 0 {
-                    let tile = build_tile(tile_id, offset_addr_glob, chr_rom);
-                    if 0 <= tile_x && 0 <= tile_y {
-                        self.draw_sprite(palette, &tile, tile_x as u8, tile_y as u8, frame_buffer);
-                    }
-                }
+        }
+        let sprite = self.sprite_line[x];
+        let sprite_pixel = sprite & 0x03;
+        if show_sprite && sprite_pixel > 0 {
+            if (sprite & 0x20) > 0 && bg_pixel > 0 && x != 255 {
+                self.ppu_reg[2] |= 0x40;
+            }
+            if (sprite & 0x10) == 0 || bg_pixel == 0 {
+                color_id = self.get_sp_color_id(((sprite >> 2) & 0x03).into(), sprite_pixel.into());
             }
         }
+
+        let color_id = (color_id & 0x3F) as usize;
+        let frame_buffer_index = (self.current_line as usize * 256 + x) * 4;
+        frame_buffer[frame_buffer_index] = COLOR_PALETTE[color_id * 3];
+        frame_buffer[frame_buffer_index + 1] = COLOR_PALETTE[color_id * 3 + 1];
+        frame_buffer[frame_buffer_index + 2] = COLOR_PALETTE[color_id * 3 + 2];
+    }
+
+    // 次のラインに表示するスプライトを最大8個選ぶ(二次OAM) 9個目以降があればオーバーフロー
+    fn evaluate_sprites(&mut self, sprite_height: u16) -> Vec<usize>{
+        let (secondary_oam, overflow) = self.find_sprites(self.current_line, sprite_height);
+        if overflow {
+            self.ppu_reg[2] |= 0x20;
+        }
+        secondary_oam
     }
 
-    fn draw_sprite(&self, palette: u8, tile: &Vec<u8>, tile_x: u8, tile_y: u8, frame_buffer: &mut [u8]){
-        for color_table_index in 0..64 {
-            let x = tile_x + (color_table_index % 8);
-            let y = tile_y + (color_table_index / 8);
-            let tmp = tile[color_table_index as usize] & 0xFF;
-            
-            let color_id = self.get_sp_color_id(palette.into(), tmp.into());
-            if color_id > 0 { // 背景色ではない
-                let r = COLOR_PALETTE[(color_id * 3 + 0) as usize];
-                let g = COLOR_PALETTE[(color_id * 3 + 1) as usize];
-                let b = COLOR_PALETTE[(color_id * 3 + 2) as usize];
-                let frame_buffer_index: u32 = (y as u16 * 256 + x as u16) as u32;
-                frame_buffer[(frame_buffer_index * 4 + 0) as usize] = r;
-                frame_buffer[(frame_buffer_index * 4 + 1) as usize] = g;
-                frame_buffer[(frame_buffer_index * 4 + 2) as usize] = b;
+    fn find_sprites(&self, line: u16, sprite_height: u16) -> (Vec<usize>, bool){
+        let in_range = |sprite_y: u8| {
+            // OAMのY座標+1のラインから表示される
+            let top = sprite_y as u16 + 1;
+            top <= line && line < top + sprite_height
+        };
+        let mut secondary_oam = Vec::with_capacity(8);
+        let mut n = 0;
+        while n < 64 && secondary_oam.len() < 8 {
+            if in_range(self.ppu_oam[n * 4]) {
+                secondary_oam.push(n);
+            }
+            n += 1;
+        }
+        // 実機のバグ: 8個見つかった後はY座標以外のバイトも誤ってY座標として比較される
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.ppu_oam[n * 4 + m]) {
+                return (secondary_oam, true);
             }
+            n += 1;
+            m = (m + 1) & 0x03;
         }
+        (secondary_oam, false)
     }
 
-    fn draw_bg(&self, palette: u8, tile: &Vec<u8>, tile_x: u8, tile_y: u8, frame_buffer: &mut [u8]){
-        for color_table_index in 0..64 {
-            let x = tile_x  + (color_table_index % 8);
-            let y = tile_y + (color_table_index / 8);
-            let tmp = tile[color_table_index as usize] & 0xFF;
-            
-            let color_id = self.get_bg_color_id(palette.into(), tmp.into());
-            let r = COLOR_PALETTE[(color_id * 3 + 0) as usize];
-            let g = COLOR_PALETTE[(color_id * 3 + 1) as usize];
-            let b = COLOR_PALETTE[(color_id * 3 + 2) as usize];
-            let frame_buffer_index: u32 = (y as u16 * 256 + x as u16) as u32;
-            frame_buffer[(frame_buffer_index * 4 + 0) as usize] = r;
-            frame_buffer[(frame_buffer_index * 4 + 1) as usize] = g;
-            frame_buffer[(frame_buffer_index * 4 + 2) as usize] = b;
+    // 実機のフェッチタイミングでパターンテーブルのアドレスをマッパーに通知する
+    // BGはドット1-256と321-336で8ドットごと、スプライトはドット257-320で8個分
+    // マッパーはA12しか見ないのでBGのタイル番号は省略する
+    fn notify_pattern_fetch(&mut self, rom: &mut Rom){
+        let dot = self.current_dot;
+        if dot == 257 {
+            self.sprite_fetch_addrs = self.next_line_sprite_addrs();
         }
+        let address = match dot {
+            1..=256 | 321..=336 if (dot - 1) % 8 == 4 => {
+                if (self.ppu_reg[0] & 0x10) > 0 {0x1000} else {0}
+            },
+            257..=320 if (dot - 257) % 8 == 4 => self.sprite_fetch_addrs[((dot - 257) / 8) as usize],
+            _ => return
+        };
+        rom.mapper.ppu_address(address);
     }
-}
 
+    fn next_line_sprite_addrs(&self) -> [u16; 8]{
+        let large_sprite = (self.ppu_reg[0] & 0x20) > 0;
+        let next_line = if self.current_line == 261 {0} else {self.current_line + 1};
+        let (secondary_oam, _) = self.find_sprites(next_line, if large_sprite {16} else {8});
+        let mut addrs = [0u16; 8];
+        for (i, addr) in addrs.iter_mut().enumerate() {
+            // 空きスロットはタイル$FFを読む
+            let tile_index = secondary_oam.get(i).map_or(0xFF, |&n| self.ppu_oam[n * 4 + 1]) as u16;
+            *addr = if large_sprite {
+                ((tile_index & 0x01) << 12) | ((tile_index & 0xFE) << 4)
+            } else {
+                let pattern_table = if (self.ppu_reg[0] & 0x08) > 0 {0x1000} else {0};
+                pattern_table | (tile_index << 4)
+            };
+        }
+        addrs
+    }
 
-fn build_tile(tile_id: u8, offset_addr: u32, chr_rom: &[u8]) -> Vec<u8>{
-    let tile_id_offset_address = tile_id as u32 * 16;
+    // current_lineに表示するスプライトを評価し、X座標ごとのピクセルをsprite_lineに求める
+    fn prepare_sprite_line(&mut self, rom: &Rom){
+        self.sprite_line = [0; 256];
+        if !self.is_rendering_enabled() {
+            return;
+        }
+        let large_sprite = (self.ppu_reg[0] & 0x20) > 0; // スプライトサイズ8 * 16の場合
+        let sprite_height = if large_sprite {16} else {8};
+        let secondary_oam = self.evaluate_sprites(sprite_height);
 
-    let mut color_tables = vec![0u8; 64];
+        for sprite_index in secondary_oam {
+            let sprite_addr = sprite_index * 4;
+            let sprite_y = self.ppu_oam[sprite_addr] as u16;
+            let tile_index = self.ppu_oam[sprite_addr + 1];
+            let attr = self.ppu_oam[sprite_addr + 2];
+            let sprite_x = self.ppu_oam[sprite_addr + 3] as usize;
+            let flip_horizontal = (attr & 0x40) > 0;
+            let flip_vertical = (attr & 0x80) > 0;
+            // パレットと背面指定はsprite_lineのbit2-4と同じ位置
+            let mut flags = ((attr & 0x03) << 2) | ((attr & 0x20) >> 1);
+            if sprite_index == 0 {
+                flags |= 0x20;
+            }
+
+            let mut row = self.current_line - (sprite_y + 1);
+            if flip_vertical {
+                row = sprite_height - 1 - row;
+            }
+            let (pattern_table, tile_id) = if large_sprite {
+                // レジスタ $2000 によるパターンテーブル選択を無視する
+                let pattern_table = if (tile_index & 0x01) > 0 {0x1000} else {0};
+                (pattern_table, (tile_index & 0xFE) as u32 + (row / 8) as u32)
+            } else {
+                let pattern_table = if (self.ppu_reg[0] & 0x08) > 0 {0x1000} else {0};
+                (pattern_table, tile_index as u32)
+            };
+            let pattern_addr = (pattern_table + tile_id * 16 + (row % 8) as u32) as u16;
 
-    for chr_index in 0..8{ // 前半
-        let chr_value = chr_rom[(offset_addr + tile_id_offset_address + chr_index)as usize];
-        let y_cache_index = chr_index * 8;
-        for xIndex in 0..8{
-            let shift = 7 - xIndex;
-            color_tables[(y_cache_index + xIndex) as usize] = (chr_value & (1 << shift)) >> shift;
+            for column in 0..8 {
+                let x = sprite_x + column;
+                if x >= 256 {
+                    break;
+                }
+                let shift = if flip_horizontal {column as u32} else {7 - column as u32};
+                let pixel = self.get_pattern_pixel(pattern_addr, shift, rom);
+                // OAMで先のスプライトの不透明ピクセルが優先 背面指定でも後ろのスプライトは隠れる
+                if pixel == 0 || (self.sprite_line[x] & 0x03) > 0 {
+                    continue;
+                }
+                self.sprite_line[x] = flags | pixel;
+            }
+        }
+    }
+
+    // パターンテーブルの1ライン分(下位/上位プレーン)からshiftビット目のピクセル値を取得する
+    fn get_pattern_pixel(&self, pattern_addr: u16, shift: u32, rom: &Rom) -> u8{
+        let lower = (self.read_bus(pattern_addr, rom) >> shift) & 0x01;
+        let upper = (self.read_bus(pattern_addr + 8, rom) >> shift) & 0x01;
+        (upper << 1) | lower
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter){
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.palette_ram);
+        w.write_u8(self.read_buffer);
+        w.write_bytes(&self.ppu_oam);
+        w.write_bytes(&self.ppu_reg);
+        w.write_u16(self.vram_addr);
+        w.write_u16(self.tmp_vram_addr);
+        w.write_u8(self.fine_x);
+        w.write_bool(self.write_toggle);
+        for value in self.bg_pattern_shift.iter().chain(self.bg_palette_shift.iter()) {
+            w.write_u16(*value);
         }
+        w.write_bytes(&self.bg_next_tile);
+        w.write_u16(self.current_line);
+        w.write_u16(self.current_dot);
+        w.write_bool(self.nmi_output);
+        w.write_u8(self.nmi_delay);
+        w.write_bool(self.suppress_vblank);
+        w.write_bytes(&self.sprite_line);
+        for addr in self.sprite_fetch_addrs.iter() {
+            w.write_u16(*addr);
+        }
+        w.write_bool(self.odd_frame);
+        w.write_u64(self.frame_count);
     }
-    for chr_index in 0..8{ // 後半
-        let chr_value = chr_rom[(offset_addr + tile_id_offset_address + 8 + chr_index)as usize];
-        let y_cache_index = chr_index * 8;
-        for xIndex in 0..8{
-            let shift = 7 - xIndex;
-            color_tables[(y_cache_index + xIndex) as usize] += ((chr_value & (1 << shift)) >> shift) * 2;
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        r.read_bytes_into(&mut self.vram)?;
+        r.read_bytes_into(&mut self.palette_ram)?;
+        self.read_buffer = r.read_u8()?;
+        r.read_bytes_into(&mut self.ppu_oam)?;
+        r.read_bytes_into(&mut self.ppu_reg)?;
+        self.vram_addr = r.read_u16()?;
+        self.tmp_vram_addr = r.read_u16()?;
+        self.fine_x = r.read_u8()? & 0x07;
+        self.write_toggle = r.read_bool()?;
+        for value in self.bg_pattern_shift.iter_mut().chain(self.bg_palette_shift.iter_mut()) {
+            *value = r.read_u16()?;
+        }
+        r.read_bytes_into(&mut self.bg_next_tile)?;
+        if self.bg_next_tile[2] > 3 {
+            return Err(StateError::Corrupted);
         }
+        self.current_line = r.read_u16()?;
+        self.current_dot = r.read_u16()?;
+        if self.current_line >= 262 || self.current_dot >= 341 {
+            return Err(StateError::Corrupted);
+        }
+        self.nmi_output = r.read_bool()?;
+        self.nmi_delay = r.read_u8()?;
+        self.suppress_vblank = r.read_bool()?;
+        r.read_bytes_into(&mut self.sprite_line)?;
+        for addr in self.sprite_fetch_addrs.iter_mut() {
+            *addr = r.read_u16()?;
+        }
+        self.odd_frame = r.read_bool()?;
+        self.frame_count = r.read_u64()?;
+        Ok(())
     }
-    color_tables
-}
\ No newline at end of file
+}
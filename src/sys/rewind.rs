@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+
+// キーフレームを取ってから次のキーフレームまでのスナップショット数
+const KEYFRAME_INTERVAL: usize = 30;
+
+// 巻き戻し用のセーブステートの履歴
+// interval_framesフレームごとにセーブステートを取り、直近のキーフレームとのXORの差分を圧縮して持つ
+// 差分はほとんどが0になるので0の連続を詰めるだけで十分小さくなる
+pub struct RewindBuffer {
+    interval_frames: u64,
+    budget_bytes: usize,
+    snapshots: VecDeque<Snapshot>,
+    keyframe: Option<Vec<u8>>, // 差分の基準にしている展開済みのキーフレーム
+    since_keyframe: usize
+}
+
+struct Snapshot {
+    frame: u64,
+    is_keyframe: bool, // falseなら直前のキーフレームとのXORの差分
+    data: Vec<u8> // compressで圧縮した内容
+}
+
+impl RewindBuffer {
+    // budget_bytesは圧縮後のスナップショットと展開済みのキーフレームの合計の上限
+    // 超えたら古いキーフレームから、それに続く差分とまとめて捨てる
+    // 最新のキーフレームの組だけでも超える場合はその組の古い差分から捨てる
+    pub fn new(interval_frames: u64, budget_bytes: usize) -> RewindBuffer {
+        RewindBuffer{
+            interval_frames: std::cmp::max(interval_frames, 1),
+            budget_bytes,
+            snapshots: VecDeque::new(),
+            keyframe: None,
+            since_keyframe: 0
+        }
+    }
+
+    pub fn is_due(&self, frame: u64) -> bool {
+        frame.is_multiple_of(self.interval_frames)
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        let keyframe_size = self.keyframe.as_ref().map_or(0, |k| k.len());
+        self.snapshots.iter().map(|s| s.data.len()).sum::<usize>() + keyframe_size
+    }
+
+    // 最も古いスナップショットのフレーム
+    pub fn oldest_frame(&self) -> Option<u64> {
+        self.snapshots.front().map(|s| s.frame)
+    }
+
+    pub fn push(&mut self, frame: u64, state: Vec<u8>) {
+        let keyframe = match &self.keyframe {
+            // セーブステートの長さはROMごとに決まっているが、違えばキーフレームを取り直す
+            Some(keyframe) if self.since_keyframe < KEYFRAME_INTERVAL && keyframe.len() == state.len() => keyframe,
+            _ => {
+                self.snapshots.push_back(Snapshot{frame, is_keyframe: true, data: compress(&state)});
+                self.keyframe = Some(state);
+                self.since_keyframe = 1;
+                self.evict();
+                return;
+            }
+        };
+        let delta: Vec<u8> = state.iter().zip(keyframe.iter()).map(|(a, b)| a ^ b).collect();
+        self.snapshots.push_back(Snapshot{frame, is_keyframe: false, data: compress(&delta)});
+        self.since_keyframe += 1;
+        self.evict();
+    }
+
+    // 使用量が上限を超えている間、最も古いキーフレームとその差分を捨てる
+    // 差分の基準にしている最新のキーフレームの組だけになったら、キーフレームは残して古い差分から捨てる
+    // キーフレームだけでも上限を超えるならすべて捨てる
+    fn evict(&mut self) {
+        while self.memory_usage() > self.budget_bytes {
+            let next_keyframe = self.snapshots.iter().skip(1).position(|s| s.is_keyframe);
+            match next_keyframe {
+                Some(index) => {
+                    self.snapshots.drain(..=index);
+                },
+                None if self.snapshots.len() > 1 => {
+                    self.snapshots.remove(1);
+                },
+                None => self.clear()
+            }
+        }
+    }
+
+    // frameかそれより前で最も新しいスナップショットを展開し、(位置, そのフレーム, セーブステート)を返す
+    pub fn find(&self, frame: u64) -> Option<(usize, u64, Vec<u8>)> {
+        let index = self.snapshots.iter().rposition(|s| s.frame <= frame)?;
+        let keyframe_index = self.snapshots.iter().take(index + 1).rposition(|s| s.is_keyframe)?;
+        let mut state = decompress(&self.snapshots[keyframe_index].data);
+        if index != keyframe_index {
+            let delta = decompress(&self.snapshots[index].data);
+            for (byte, d) in state.iter_mut().zip(delta.iter()) {
+                *byte ^= d;
+            }
+        }
+        Some((index, self.snapshots[index].frame, state))
+    }
+
+    // findで返した位置より新しいスナップショットを捨てる 状態を読み込めてから呼ぶ
+    pub fn truncate_after(&mut self, index: usize) {
+        self.snapshots.truncate(index + 1);
+        // 以降の差分は次に取るキーフレームを基準にする
+        self.keyframe = None;
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe = None;
+    }
+}
+
+// 0の連続とそれ以外のバイト列を交互に並べる
+// 0x00 長さ: 長さ分の0  0x01 長さ データ: そのままのバイト列  長さは7bitずつの可変長
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let start = pos;
+        if data[pos] == 0 {
+            while pos < data.len() && data[pos] == 0 {
+                pos += 1;
+            }
+            out.push(0x00);
+            write_length(&mut out, pos - start);
+        } else {
+            // 0が2つ以上続くまでは生データとしてまとめる
+            while pos < data.len() && !(data[pos] == 0 && data.get(pos + 1) == Some(&0)) {
+                pos += 1;
+            }
+            out.push(0x01);
+            write_length(&mut out, pos - start);
+            out.extend_from_slice(&data[start..pos]);
+        }
+    }
+    out
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let tag = data[pos];
+        pos += 1;
+        let length = read_length(data, &mut pos);
+        if tag == 0x00 {
+            out.resize(out.len() + length, 0);
+        } else {
+            out.extend_from_slice(&data[pos..pos + length]);
+            pos += length;
+        }
+    }
+    out
+}
+
+fn write_length(out: &mut Vec<u8>, mut length: usize) {
+    while length >= 0x80 {
+        out.push((length & 0x7F) as u8 | 0x80);
+        length >>= 7;
+    }
+    out.push(length as u8);
+}
+
+fn read_length(data: &[u8], pos: &mut usize) -> usize {
+    let mut length = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        length |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return length;
+        }
+        shift += 7;
+    }
+}
@@ -1,41 +1,240 @@
+use std::fmt;
+
+use super::mapper::{Chr, Mapper, create_mapper};
 
 pub struct Rom {
-    pub prg_rom: Vec<u8>,
-    pub chr_rom: Vec<u8>
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub mirroring: Mirroring,
+    pub mapper_number: u16,
+    pub submapper_number: u8,
+    pub prg_ram_size: usize, // バッテリーバックアップされないPRG-RAM
+    pub prg_nvram_size: usize, // バッテリーバックアップされるPRG-RAM
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub battery: bool,
+    pub trainer: Option<Vec<u8>>, // $7000-$71FFに配置される512バイト
+    pub console_type: ConsoleType,
+    pub timing: Timing,
+    pub is_nes2: bool,
+    pub hash: u64, // PRG ROMとCHR ROMのハッシュ セーブステートのROMの照合に使う
+    pub mapper: Box<dyn Mapper>
+}
+
+// ネームテーブルのミラーリング
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    SingleScreenLower,
+    SingleScreenUpper
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    Extended(u8) // NES 2.0のbyte13で指定される拡張コンソール
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum RomError {
+    InvalidMagic,
+    TruncatedHeader,
+    TruncatedTrainer,
+    TruncatedPrgRom{expected: usize, actual: usize},
+    TruncatedChrRom{expected: usize, actual: usize},
+    MissingPrgRom,
+    RomSizeOverflow, // NES 2.0の指数表記のサイズが扱える範囲を超えている
+    UnsupportedMapper(u16)
 }
 
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::InvalidMagic => write!(f, "not an iNES file (missing \"NES\\x1A\")"),
+            RomError::TruncatedHeader => write!(f, "file is shorter than the 16-byte header"),
+            RomError::TruncatedTrainer => write!(f, "trainer is truncated"),
+            RomError::TruncatedPrgRom{expected, actual} => write!(f, "PRG ROM is truncated ({} of {} bytes)", actual, expected),
+            RomError::TruncatedChrRom{expected, actual} => write!(f, "CHR ROM is truncated ({} of {} bytes)", actual, expected),
+            RomError::MissingPrgRom => write!(f, "PRG ROM size is zero"),
+            RomError::RomSizeOverflow => write!(f, "ROM size in the header is too large"),
+            RomError::UnsupportedMapper(number) => write!(f, "mapper {} is not supported", number)
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
 const INES_HEADER_SIZE: usize = 0x10;
+const TRAINER_SIZE: usize = 0x200;
 
+// NES 2.0のROMサイズ 上位ニブルが$Fなら指数表記 2^E * (MM * 2 + 1)
+// 指数が大きすぎてusizeに収まらなければNone
+fn nes2_rom_size(lsb: u8, msb: u8, unit: usize) -> Option<usize> {
+    if msb == 0x0F {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x03) as usize * 2 + 1;
+        return 1usize.checked_shl(exponent)?.checked_mul(multiplier);
+    }
+    Some((((msb as usize) << 8) | lsb as usize) * unit)
+}
+
+// NES 2.0のRAMサイズ 0なら無し、それ以外は64 << n バイト
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {0} else {64 << shift}
+}
+
+// iNES/NES 2.0ヘッダを解析してカートリッジを生成する
+pub fn from_array(rom: &[u8]) -> Result<Rom, RomError> {
+    if rom.len() < 4 || &rom[0..4] != b"NES\x1A" {
+        return Err(RomError::InvalidMagic);
+    }
+    if rom.len() < INES_HEADER_SIZE {
+        return Err(RomError::TruncatedHeader);
+    }
+    let is_nes2 = (rom[7] & 0x0C) == 0x08;
+    let mirroring = if (rom[6] & 0x08) > 0 {
+        Mirroring::FourScreen
+    } else if (rom[6] & 0x01) > 0 {
+        Mirroring::Vertical
+    } else {
+        Mirroring::Horizontal
+    };
+    let battery = (rom[6] & 0x02) > 0;
+    let has_trainer = (rom[6] & 0x04) > 0;
+    let mut console_type = match rom[7] & 0x03 {
+        0 => ConsoleType::Nes,
+        1 => ConsoleType::VsSystem,
+        2 => ConsoleType::Playchoice10,
+        _ => ConsoleType::Extended(0)
+    };
+
+    let prg_rom_size;
+    let chr_rom_size;
+    let mapper_number;
+    let mut submapper_number = 0;
+    let mut prg_ram_size;
+    let mut prg_nvram_size = 0;
+    let mut chr_ram_size = 0;
+    let mut chr_nvram_size = 0;
+    let timing;
+    if is_nes2 {
+        prg_rom_size = nes2_rom_size(rom[4], rom[9] & 0x0F, 0x4000).ok_or(RomError::RomSizeOverflow)?;
+        chr_rom_size = nes2_rom_size(rom[5], rom[9] >> 4, 0x2000).ok_or(RomError::RomSizeOverflow)?;
+        mapper_number = (((rom[8] & 0x0F) as u16) << 8) | (rom[7] & 0xF0) as u16 | (rom[6] >> 4) as u16;
+        submapper_number = rom[8] >> 4;
+        prg_ram_size = nes2_ram_size(rom[10] & 0x0F);
+        prg_nvram_size = nes2_ram_size(rom[10] >> 4);
+        chr_ram_size = nes2_ram_size(rom[11] & 0x0F);
+        chr_nvram_size = nes2_ram_size(rom[11] >> 4);
+        timing = match rom[12] & 0x03 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy
+        };
+        if console_type == ConsoleType::Extended(0) {
+            console_type = ConsoleType::Extended(rom[13] & 0x0F);
+        }
+    } else {
+        prg_rom_size = rom[4] as usize * 0x4000;
+        chr_rom_size = rom[5] as usize * 0x2000;
+        // byte12-15にゴミ("DiskDude!"など)が書かれた古いダンプではbyte7を信用しない
+        let upper = if rom[12..16].iter().all(|&b| b == 0) {rom[7] & 0xF0} else {0};
+        mapper_number = (upper | (rom[6] >> 4)) as u16;
+        // PRG-RAMのサイズは8KB単位 0でも互換性のため8KBとみなす
+        prg_ram_size = std::cmp::max(rom[8] as usize, 1) * 0x2000;
+        if chr_rom_size == 0 {
+            chr_ram_size = 0x2000;
+        }
+        timing = if (rom[9] & 0x01) > 0 {Timing::Pal} else {Timing::Ntsc};
+    }
+    if prg_rom_size == 0 {
+        return Err(RomError::MissingPrgRom);
+    }
+    if battery && !is_nes2 {
+        // iNESではバッテリーバックアップの有無しか分からない
+        prg_nvram_size = prg_ram_size;
+        prg_ram_size = 0;
+    }
+
+    let mut offset = INES_HEADER_SIZE;
+    let trainer = if has_trainer {
+        if rom.len() < offset + TRAINER_SIZE {
+            return Err(RomError::TruncatedTrainer);
+        }
+        offset += TRAINER_SIZE;
+        Some(rom[INES_HEADER_SIZE .. offset].to_vec())
+    } else {
+        None
+    };
 
-pub fn from_array(rom: &[u8]) -> Rom {
-    // TODO headerチェック
-    let prg_rom_size_kb: usize  = (rom[4] as usize) * 16;
-    let chr_rom_size_kb: usize  = (rom[5] as usize) * 8;
-    let mirroring = rom[6] & 0x01;
-
-    let prg_rom_size= prg_rom_size_kb * 1024;
-    let range = INES_HEADER_SIZE .. (INES_HEADER_SIZE + prg_rom_size_kb * 1024);
-
-    // if mirroring > 0 {
-    //     // TODO: ちゃんとミラーされるようにコピーをやめる
-    //     // TODO: アクセスの際に上位ビット無視すればミラーと同じ挙動？
-    //     let tmp_rom = &rom[range];
-    //     let mirror_rom: [u8; 32 * 1024] = [0; 32 * 1024];
-        
-    //     System.arraycopy(tmp_rom, 0, mirror_rom, 0, tmp_rom.len());
-        
-    //     if tmp_rom.len() < 32 * 1024 && tmp_rom.len() + tmp_rom.len() <= 32 * 1024 {
-    //         System.arraycopy(tmp_rom, 0, mirror_rom, tmp_rom.len(), tmp_rom.len());
-    //     }
-    //     prg_rom = mirror_rom;
-    // }
-    // else{
-    //     let tmp_rom = &rom[range];
-    //     prg_rom = tmp_rom;
-    // }
-    let prg_rom = rom[range].to_vec();
-
-    let chr_rom_start_addr: usize = INES_HEADER_SIZE + prg_rom_size_kb * 1024;
-    let chr_rom = rom[chr_rom_start_addr .. (chr_rom_start_addr + chr_rom_size_kb * 1024)].to_vec();
-    Rom{prg_rom, chr_rom}
-}
\ No newline at end of file
+    let available = rom.len() - offset;
+    if available < prg_rom_size {
+        return Err(RomError::TruncatedPrgRom{expected: prg_rom_size, actual: available});
+    }
+    let prg_rom_end = offset.checked_add(prg_rom_size).ok_or(RomError::RomSizeOverflow)?;
+    let prg_rom = rom[offset .. prg_rom_end].to_vec();
+    offset = prg_rom_end;
+
+    let available = rom.len() - offset;
+    if available < chr_rom_size {
+        return Err(RomError::TruncatedChrRom{expected: chr_rom_size, actual: available});
+    }
+    let chr_rom_end = offset.checked_add(chr_rom_size).ok_or(RomError::RomSizeOverflow)?;
+    let hash = fnv1a_hash(&rom[offset - prg_rom_size .. chr_rom_end]);
+    let chr = if chr_rom_size == 0 {
+        // CHR ROMが無ければCHR RAM NES 2.0でサイズ指定が無い場合も8KB確保する
+        if chr_ram_size + chr_nvram_size == 0 {
+            chr_ram_size = 0x2000;
+        }
+        Chr::ram(chr_ram_size + chr_nvram_size)
+    } else {
+        Chr::rom(rom[offset .. chr_rom_end].to_vec())
+    };
+
+    let mut mapper = create_mapper(mapper_number, prg_rom, chr, prg_ram_size + prg_nvram_size, mirroring)
+        .ok_or(RomError::UnsupportedMapper(mapper_number))?;
+    if let (Some(trainer), Some(ram)) = (&trainer, mapper.prg_ram_mut().get_mut(0x1000..0x1000 + TRAINER_SIZE)) {
+        ram.copy_from_slice(trainer);
+    }
+    Ok(Rom{
+        prg_rom_size,
+        chr_rom_size,
+        mirroring,
+        mapper_number,
+        submapper_number,
+        prg_ram_size,
+        prg_nvram_size,
+        chr_ram_size,
+        chr_nvram_size,
+        battery,
+        trainer,
+        console_type,
+        timing,
+        is_nes2,
+        hash,
+        mapper
+    })
+}
+
+// 64bit FNV-1a
+fn fnv1a_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xCBF2_9CE4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01B3);
+    }
+    hash
+}
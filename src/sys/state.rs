@@ -0,0 +1,154 @@
+use std::fmt;
+
+// セーブステートのバイナリ形式
+// "RNST" バージョン(u16) ROMのハッシュ(u64) に続けて各コンポーネントの状態を決まった順に並べる
+// 数値はすべてリトルエンディアン
+const STATE_MAGIC: &[u8; 4] = b"RNST";
+pub const STATE_VERSION: u16 = 4;
+
+#[derive(Clone, PartialEq, Debug)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    RomMismatch,
+    Truncated,
+    Corrupted
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "save state version {} is not supported", version),
+            StateError::RomMismatch => write!(f, "save state was made with a different ROM"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupted => write!(f, "save state is corrupted")
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub struct StateWriter {
+    buf: Vec<u8>
+}
+
+impl StateWriter {
+    pub fn new(rom_hash: u64) -> StateWriter {
+        let mut writer = StateWriter{buf: Vec::new()};
+        writer.buf.extend_from_slice(STATE_MAGIC);
+        writer.write_u16(STATE_VERSION);
+        writer.write_u64(rom_hash);
+        writer
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    // 長さ(u32)付きのバイト列
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_u32(data.len() as u32);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> StateReader<'a> {
+    // ヘッダを確認して、続く各コンポーネントの読み込み位置から始める
+    pub fn new(data: &'a [u8], rom_hash: u64) -> Result<StateReader<'a>, StateError> {
+        let mut reader = StateReader{data, pos: 0};
+        if reader.take(STATE_MAGIC.len()).map_err(|_| StateError::InvalidMagic)? != STATE_MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+        let version = reader.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if reader.read_u64()? != rom_hash {
+            return Err(StateError::RomMismatch);
+        }
+        Ok(reader)
+    }
+
+    fn take(&mut self, size: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < size {
+            return Err(StateError::Truncated);
+        }
+        let slice = &self.data[self.pos .. self.pos + size];
+        self.pos += size;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupted)
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(self.take(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.take(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    // write_bytesで書いたバイト列を同じ長さのバッファへ読み込む
+    pub fn read_bytes_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        let size = self.read_u32()? as usize;
+        if size != dest.len() {
+            return Err(StateError::Corrupted);
+        }
+        dest.copy_from_slice(self.take(size)?);
+        Ok(())
+    }
+
+    // 余分なデータが残っていれば壊れている
+    pub fn finish(&self) -> Result<(), StateError> {
+        if self.pos != self.data.len() {
+            return Err(StateError::Corrupted);
+        }
+        Ok(())
+    }
+}
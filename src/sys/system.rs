@@ -1,10 +1,12 @@
-use super::{cpu::Cpu, memory_map::MemoryMap, ppu::Ppu, rom::Rom};
+use super::{audio::{AudioOutput, DEFAULT_SAMPLE_RATE}, cpu::{Cpu, IRQ_DMC, IRQ_FRAME_COUNTER, IRQ_MAPPER}, memory_map::MemoryMap, ppu::Ppu, rewind::RewindBuffer, rom::Rom, state::{StateError, StateReader, StateWriter}};
 
 pub struct Nes {
     pub memory_map: MemoryMap,
     pub cpu: Cpu,
     pub frame_buffer: Vec<u8>,
-    pub cycle_acc: u32
+    pub audio: AudioOutput,
+    pub cycle_acc: u64,
+    pub rewind: Option<RewindBuffer>
 }
 
 impl Nes {
@@ -21,7 +23,7 @@ impl Nes {
             }
         }
 
-        Nes{memory_map, cpu, frame_buffer, cycle_acc: 0}
+        Nes{memory_map, cpu, frame_buffer, audio: AudioOutput::new(DEFAULT_SAMPLE_RATE), cycle_acc: 0, rewind: None}
     }
 
     pub fn reset(&mut self){
@@ -32,11 +34,141 @@ impl Nes {
         self.cpu.init();
     }
 
+    // 各ボタンの押下状態を BUTTON_A | BUTTON_START のようなビットの組み合わせで指定する
+    // port 0が1P、1が2P それ以外のポートは無視する
+    pub fn set_buttons(&mut self, port: usize, buttons: u8){
+        if let Some(controller) = self.memory_map.controllers.get_mut(port) {
+            controller.set_buttons(buttons);
+        }
+    }
+
+    // バッテリーバックアップされたPRG-RAMの内容 バッテリーが無いカートリッジではNone
+    pub fn save_data(&self) -> Option<Vec<u8>>{
+        if !self.memory_map.rom.battery {
+            return None;
+        }
+        Some(self.memory_map.rom.mapper.prg_ram().to_vec())
+    }
+
+    // save_dataで取り出した内容を書き戻す サイズが異なる場合は先頭から入る分だけ読み込む
+    pub fn load_save_data(&mut self, data: &[u8]){
+        let prg_ram = self.memory_map.rom.mapper.prg_ram_mut();
+        let size = std::cmp::min(prg_ram.len(), data.len());
+        prg_ram[..size].copy_from_slice(&data[..size]);
+    }
+
+    // CPU、PPU、APU、マッパーのレジスタとカートリッジのRAMを含む全体の状態
+    // 画面と音声の出力バッファは含まない
+    pub fn save_state(&self) -> Vec<u8>{
+        let mut w = StateWriter::new(self.memory_map.rom.hash);
+        self.cpu.save_state(&mut w);
+        self.memory_map.save_state(&mut w);
+        w.write_u64(self.cycle_acc);
+        w.into_bytes()
+    }
+
+    // save_stateで保存した状態に戻す 別のROMの状態や壊れたデータの場合はエラーを返し、現在の状態は変えない
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError>{
+        let mut r = StateReader::new(data, self.memory_map.rom.hash)?;
+        let backup = self.save_state();
+        let result = self.read_state(&mut r);
+        if result.is_err() {
+            let mut backup_reader = StateReader::new(&backup, self.memory_map.rom.hash)?;
+            self.read_state(&mut backup_reader)?;
+        }
+        result
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) -> Result<(), StateError>{
+        self.cpu.load_state(r)?;
+        self.memory_map.load_state(r)?;
+        self.cycle_acc = r.read_u64()?;
+        r.finish()
+    }
+
+    // interval_framesフレームごとにセーブステートを取って巻き戻せるようにする
+    // budget_bytesは履歴に使うメモリの上限
+    pub fn enable_rewind(&mut self, interval_frames: u64, budget_bytes: usize){
+        self.rewind = Some(RewindBuffer::new(interval_frames, budget_bytes));
+    }
+
+    pub fn disable_rewind(&mut self){
+        self.rewind = None;
+    }
+
+    // framesフレーム前かそれより前で最も近いスナップショットに戻し、実際に戻ったフレーム数を返す
+    // 履歴が無ければOk(None) スナップショットを読み込めなければエラーを返し、状態も履歴も変えない
+    pub fn rewind(&mut self, frames: u64) -> Result<Option<u64>, StateError>{
+        let current = self.memory_map.ppu.frame_count;
+        let (index, frame, state) = match self.rewind.as_ref().and_then(|rewind| rewind.find(current.saturating_sub(frames))) {
+            Some(snapshot) => snapshot,
+            None => return Ok(None)
+        };
+        self.load_state(&state)?;
+        if let Some(rewind) = self.rewind.as_mut() {
+            rewind.truncate_after(index);
+        }
+        Ok(Some(current - frame))
+    }
+
+    // 出力サンプリングレート(44100や48000)を変更する ためていたサンプルは捨てる
+    pub fn set_sample_rate(&mut self, sample_rate: u32){
+        self.audio = AudioOutput::new(sample_rate);
+    }
+
+    // 前回から生成された音声サンプル(モノラル)をすべて取り出す
+    pub fn drain_samples(&mut self) -> Vec<f32>{
+        self.audio.drain()
+    }
+
+    // PPUがVBLANKに入るまで(1フレーム分 NTSCで約29780.5 CPUサイクル)実行する
+    // 電源投入直後はプリレンダーラインから始まるので、最初の呼び出しは可視ラインを描画し終えた時点(約27508 CPUサイクル)で戻る
+    // 巻き戻しが有効なら実行後にスナップショットを取る
+    pub fn run_frame(&mut self){
+        let frame_count = self.memory_map.ppu.frame_count;
+        while self.memory_map.ppu.frame_count == frame_count {
+            self.execute();
+        }
+        let frame = self.memory_map.ppu.frame_count;
+        if self.rewind.as_ref().is_some_and(|rewind| rewind.is_due(frame)) {
+            let state = self.save_state();
+            if let Some(rewind) = self.rewind.as_mut() {
+                rewind.push(frame, state);
+            }
+        }
+    }
+
     pub fn execute(&mut self){
-        self.cpu.next_cycle(&mut self.memory_map);
-        if self.cycle_acc % 3 == 0 {
-            self.memory_map.ppu_next_cycle(&mut self.frame_buffer, &mut self.cpu);
+        let mut cycles = self.cpu.start_next_cycle(&mut self.memory_map) as u64;
+        if self.memory_map.oam_dma_pending {
+            // OAM DMAはCPUを513サイクル、奇数サイクルから始まる場合は514サイクル停止させる
+            self.memory_map.oam_dma_pending = false;
+            cycles += 513 + (self.cycle_acc + cycles) % 2;
+        }
+        // CPU 1サイクルにつきPPUは3ドット進む
+        let mut cycle = 0;
+        while cycle < cycles {
+            for _ in 0..3 {
+                self.memory_map.ppu_next_cycle(&mut self.frame_buffer, &mut self.cpu);
+            }
+            self.memory_map.apu.clock();
+            self.audio.push(self.memory_map.apu.output());
+            self.memory_map.rom.mapper.cpu_clock();
+            if let Some(address) = self.memory_map.apu.dmc.pending_read() {
+                // DMCのサンプル読み込みはCPUを4サイクル停止させる
+                let sample = self.memory_map.get_from_address(address as u32);
+                self.memory_map.apu.dmc.fill_sample_buffer(sample);
+                cycles += 4;
+            }
+            cycle += 1;
+            if cycle == 4 {
+                // 割り込みシーケンスの最初の4サイクルの間に発生したNMIはベクタを乗っ取る
+                self.cpu.finish_interrupt(&mut self.memory_map);
+            }
         }
-        self.cycle_acc += 1;
+        self.cpu.set_irq(IRQ_FRAME_COUNTER, self.memory_map.apu.frame_irq);
+        self.cpu.set_irq(IRQ_DMC, self.memory_map.apu.dmc.irq);
+        self.cpu.set_irq(IRQ_MAPPER, self.memory_map.rom.mapper.irq());
+        self.cycle_acc += cycles;
     }
 }
\ No newline at end of file
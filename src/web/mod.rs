@@ -0,0 +1,165 @@
+// ブラウザ向けのフロントエンド wasm-bindgenでJavaScriptから使う
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::Clamped;
+use web_sys::{CanvasRenderingContext2d, ImageData};
+
+use crate::load_cartridge;
+use crate::sys::{rom::Rom, system::Nes};
+use web_audio::WebAudio;
+
+mod web_audio;
+
+const SCREEN_WIDTH: u32 = 256;
+const SCREEN_HEIGHT: u32 = 240;
+
+// 0.1秒ごとにスナップショットを取り、履歴には最大16MBまで使う
+const REWIND_INTERVAL_FRAMES: u64 = 6;
+const REWIND_BUDGET_BYTES: usize = 16 * 1024 * 1024;
+
+fn js_error(message: &str) -> JsValue {
+    js_sys::Error::new(message).into()
+}
+
+// JavaScriptから new Emulator() で生成する 1ページに複数置ける
+#[wasm_bindgen]
+pub struct Emulator {
+    system: Option<Nes>,
+    rom_data: Vec<u8>, // 電源再投入時に読み直すための元のROMイメージ
+    audio: Option<WebAudio>
+}
+
+#[wasm_bindgen]
+impl Emulator {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Emulator {
+        // 音声が使えない環境でも映像は動かす
+        Emulator{system: None, rom_data: vec![], audio: WebAudio::new().ok()}
+    }
+
+    // ROMを読み込んでリセットする 読み込めなければ例外
+    pub fn load_rom(&mut self, buf: &[u8]) -> Result<String, JsValue> {
+        let rom = load_cartridge(buf).map_err(|e| js_error(&e.to_string()))?;
+        let info = format!("mapper {}\nprg_rom {}bytes\nchr_rom {}bytes\n", rom.mapper_number, rom.prg_rom_size, rom.chr_rom_size);
+        self.rom_data = buf.to_vec();
+        self.start(rom);
+        Ok(info)
+    }
+
+    pub fn is_loaded(&self) -> bool {
+        self.system.is_some()
+    }
+
+    pub fn run_frame(&mut self) -> Result<(), JsValue> {
+        let sys = self.system_mut()?;
+        sys.run_frame();
+        let samples = sys.drain_samples();
+        if let (Some(audio), Some(sys)) = (self.audio.as_ref(), self.system.as_mut()) {
+            audio.push_samples(&samples);
+            sys.audio.set_rate_adjust(audio.rate_adjust());
+        }
+        Ok(())
+    }
+
+    // RGBA 256x240のフレームバッファの先頭 wasmのメモリから直接読む場合に使う
+    pub fn frame_buffer_ptr(&self) -> Result<*const u8, JsValue> {
+        Ok(self.system_ref()?.frame_buffer.as_ptr())
+    }
+
+    pub fn draw(&self, ctx: &CanvasRenderingContext2d) -> Result<(), JsValue> {
+        let buf = &self.system_ref()?.frame_buffer;
+        let data = ImageData::new_with_u8_clamped_array_and_sh(Clamped(buf), SCREEN_WIDTH, SCREEN_HEIGHT)?;
+        ctx.put_image_data(&data, 0.0, 0.0)
+    }
+
+    // port 0が1P、1が2P ボタンはBUTTON_A | BUTTON_STARTのようなビットの組み合わせ
+    pub fn set_buttons(&mut self, port: usize, buttons: u8) -> Result<(), JsValue> {
+        if port > 1 {
+            return Err(js_error(&format!("invalid controller port {}", port)));
+        }
+        self.system_mut()?.set_buttons(port, buttons);
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> Result<(), JsValue> {
+        self.system_mut()?.reset();
+        Ok(())
+    }
+
+    // ROMを読み直して初期状態から起動する バッテリーバックアップの内容は引き継ぐ
+    pub fn power_cycle(&mut self) -> Result<(), JsValue> {
+        let save_data = self.system_ref()?.save_data();
+        let rom = load_cartridge(&self.rom_data).map_err(|e| js_error(&e.to_string()))?;
+        self.start(rom);
+        if let (Some(data), Some(sys)) = (save_data, self.system.as_mut()) {
+            sys.load_save_data(&data);
+        }
+        Ok(())
+    }
+
+    // バッテリーバックアップのセーブデータ 無ければ空
+    pub fn save_data(&self) -> Vec<u8> {
+        self.system.as_ref().and_then(|sys| sys.save_data()).unwrap_or_default()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.system_mut()?.load_save_data(data);
+        Ok(())
+    }
+
+    // エミュレータ全体の状態 同じROMでのみload_stateで戻せる
+    pub fn save_state(&self) -> Result<Vec<u8>, JsValue> {
+        Ok(self.system_ref()?.save_state())
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        self.system_mut()?.load_state(data).map_err(|e| js_error(&e.to_string()))
+    }
+
+    // framesフレーム前の付近まで巻き戻し、実際に戻ったフレーム数を返す 履歴が無ければ0
+    pub fn rewind(&mut self, frames: u32) -> Result<u32, JsValue> {
+        let rewound = self.system_mut()?.rewind(frames as u64).map_err(|e| js_error(&e.to_string()))?;
+        Ok(rewound.unwrap_or(0) as u32)
+    }
+
+    // 音量 0.0-1.0
+    pub fn set_volume(&mut self, volume: f32) {
+        if let Some(audio) = self.audio.as_mut() {
+            audio.set_volume(volume);
+        }
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        if let Some(audio) = self.audio.as_mut() {
+            audio.set_muted(muted);
+        }
+    }
+}
+
+impl Emulator {
+    fn start(&mut self, rom: Rom) {
+        let mut system = Nes::new(rom);
+        if let Some(audio) = self.audio.as_ref() {
+            // ROMの読み込みはユーザー操作を起点に呼ばれるのでここで再生を開始できる
+            audio.resume();
+            system.set_sample_rate(audio.sample_rate());
+        }
+        system.reset();
+        system.enable_rewind(REWIND_INTERVAL_FRAMES, REWIND_BUDGET_BYTES);
+        self.system = Some(system);
+    }
+
+    fn system_ref(&self) -> Result<&Nes, JsValue> {
+        self.system.as_ref().ok_or_else(|| js_error("ROM is not loaded"))
+    }
+
+    fn system_mut(&mut self) -> Result<&mut Nes, JsValue> {
+        self.system.as_mut().ok_or_else(|| js_error("ROM is not loaded"))
+    }
+}
+
+impl Default for Emulator {
+    fn default() -> Emulator {
+        Emulator::new()
+    }
+}
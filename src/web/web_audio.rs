@@ -0,0 +1,102 @@
+use std::{cell::RefCell, collections::VecDeque, rc::Rc};
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::{AudioContext, AudioProcessingEvent, GainNode, ScriptProcessorNode};
+
+const BUFFER_SIZE: u32 = 1024;
+// キューに溜めておくサンプル数の目標 これより多ければ生成を減らし、少なければ増やす
+const TARGET_QUEUE_SIZE: usize = BUFFER_SIZE as usize * 2;
+const MAX_QUEUE_SIZE: usize = TARGET_QUEUE_SIZE * 4;
+const MAX_RATE_ADJUST: f64 = 0.005;
+
+// エミュレータが生成したサンプルをScriptProcessorNodeで再生する
+pub struct WebAudio {
+    context: AudioContext,
+    gain: GainNode,
+    _processor: ScriptProcessorNode,
+    _on_audio_process: Closure<dyn FnMut(AudioProcessingEvent)>,
+    queue: Rc<RefCell<VecDeque<f32>>>,
+    volume: f32,
+    muted: bool
+}
+
+impl WebAudio {
+    pub fn new() -> Result<WebAudio, JsValue> {
+        let context = AudioContext::new()?;
+        let gain = context.create_gain()?;
+        let processor = context.create_script_processor_with_buffer_size_and_number_of_input_channels_and_number_of_output_channels(BUFFER_SIZE, 0, 1)?;
+        let queue = Rc::new(RefCell::new(VecDeque::new()));
+
+        let callback_queue = queue.clone();
+        let mut last_sample = 0.0;
+        let on_audio_process = Closure::wrap(Box::new(move |event: AudioProcessingEvent| {
+            let output = match event.output_buffer() {
+                Ok(buffer) => buffer,
+                Err(_) => return
+            };
+            let mut queue = callback_queue.borrow_mut();
+            let mut data = vec![0.0f32; output.length() as usize];
+            for sample in data.iter_mut() {
+                // 足りない分は直前の値で埋めてプチノイズを抑える
+                if let Some(s) = queue.pop_front() {
+                    last_sample = s;
+                }
+                *sample = last_sample;
+            }
+            let _ = output.copy_to_channel(&data, 0);
+        }) as Box<dyn FnMut(AudioProcessingEvent)>);
+        processor.set_onaudioprocess(Some(on_audio_process.as_ref().unchecked_ref()));
+        processor.connect_with_audio_node(&gain)?;
+        gain.connect_with_audio_node(&context.destination())?;
+
+        Ok(WebAudio{
+            context,
+            gain,
+            _processor: processor,
+            _on_audio_process: on_audio_process,
+            queue,
+            volume: 1.0,
+            muted: false
+        })
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.context.sample_rate() as u32
+    }
+
+    // ブラウザはユーザー操作があるまでAudioContextを止めているので、操作のイベント内で呼ぶ
+    pub fn resume(&self) {
+        let _ = self.context.resume();
+    }
+
+    pub fn push_samples(&self, samples: &[f32]) {
+        let mut queue = self.queue.borrow_mut();
+        queue.extend(samples);
+        if queue.len() > MAX_QUEUE_SIZE {
+            // タブが裏にあった後などで溜まりすぎた分は捨てて遅延を戻す
+            let excess = queue.len() - TARGET_QUEUE_SIZE;
+            queue.drain(..excess);
+        }
+    }
+
+    // キューの溜まり具合から、次に生成するサンプル数の倍率を決める
+    pub fn rate_adjust(&self) -> f64 {
+        let fill = self.queue.borrow().len() as f64 / TARGET_QUEUE_SIZE as f64;
+        1.0 + (1.0 - fill).clamp(-1.0, 1.0) * MAX_RATE_ADJUST
+    }
+
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+        self.apply_gain();
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+        self.apply_gain();
+    }
+
+    fn apply_gain(&self) {
+        self.gain.gain().set_value(if self.muted {0.0} else {self.volume});
+    }
+}
@@ -0,0 +1,38 @@
+// nes-headlessを実行して指定したフレーム数だけ進むことを確認する
+use std::{env, fs, process::Command};
+
+// JMP $8000を繰り返すだけのNROM
+fn write_test_rom(name: &str) -> String {
+    let mut buf = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00];
+    buf.resize(16, 0);
+    let mut prg = vec![0; 0x4000];
+    prg[0..3].copy_from_slice(&[0x4C, 0x00, 0x80]);
+    // NMI, RESET, IRQ
+    prg[0x3FFA..].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+    buf.extend(prg);
+    buf.extend(vec![0; 0x2000]);
+    let path = env::temp_dir().join(format!("rust-nes-{}-{}.nes", name, std::process::id()));
+    fs::write(&path, buf).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+#[test]
+fn headless_runs_requested_frames() {
+    let rom_path = write_test_rom("headless");
+    let output = Command::new(env!("CARGO_BIN_EXE_nes-headless"))
+        .args([rom_path.as_str(), "--frames", "3"])
+        .output()
+        .unwrap();
+    fs::remove_file(&rom_path).ok();
+    assert!(output.status.success());
+
+    // frames N cpu_cycles N samples N
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = stdout.split_whitespace().collect();
+    assert_eq!(["frames", "3", "cpu_cycles"], fields[0..3]);
+    let cycles: i64 = fields[3].parse().unwrap();
+    // 最初のフレームはプリレンダーラインからVBLANKまで、残りは1フレーム(341 * 262ドット)ずつ
+    // JMPの3サイクル単位で止まるので誤差が出る
+    let expect = (341 * 242 + 1 + 2 * 341 * 262) / 3;
+    assert!((cycles - expect).abs() <= 3, "{}", stdout);
+}
@@ -0,0 +1,184 @@
+// blarggさんなどのテストROMを実行して結果を確認する
+// テストROMはライセンスの都合で同梱していないので、NES_TEST_ROMSに置き場所のディレクトリを指定する
+// (未指定なら./test_roms) ディレクトリ以下の.nesをすべて実行し、無ければ何もせず成功とする
+// NES_TEST_ROM_FILTERを指定するとパスにその文字列を含むROMだけを実行する
+//
+//   $ NES_TEST_ROMS=~/nes-test-roms cargo test --release --test test_roms -- --nocapture
+//
+// テストROMは$6000に状態、$6001-$6003に目印のDE B0 61、$6004からNUL終端の結果のテキストを書く
+// 状態は$80が実行中、$81がリセット要求、それ以外は終了で$00が成功
+// $6000に書かない古いROMは画面(ネームテーブル)に表示されたPassed/Failedの文字で判定し、どちらも出なければ失敗とする
+// 実行中にパニックしたROMも失敗とする
+use std::{env, fs, panic, path::{Path, PathBuf}};
+
+use rust_nes::sys::{rom, system::Nes};
+
+const STATUS_ADDRESS: u32 = 0x6000;
+const SIGNATURE_ADDRESS: u32 = 0x6001;
+const TEXT_ADDRESS: u32 = 0x6004;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+
+// 目印が現れるのを待つフレーム数 これを過ぎても無ければ$6000の結果出力に対応していないROM
+const SIGNATURE_TIMEOUT_FRAMES: u64 = 600;
+// 1つのROMの実行時間の上限 長いものでも1分程度で終わるので余裕を見て2分
+const TIMEOUT_FRAMES: u64 = 60 * 120;
+// リセット要求から実際にリセットするまでの時間 100ms以上空ける必要がある
+const RESET_DELAY_FRAMES: u64 = 10;
+
+enum Outcome {
+    Passed(String),
+    Failed(Option<u8>, String), // $6000の状態 画面で判定した場合はNone
+    Timeout(String),
+    Error(String)
+}
+
+fn read_text(nes: &mut Nes) -> String {
+    let mut text = Vec::new();
+    let mut address = TEXT_ADDRESS;
+    while address < 0x8000 {
+        let c = nes.memory_map.get_from_address(address);
+        if c == 0 {
+            break;
+        }
+        text.push(c);
+        address += 1;
+    }
+    String::from_utf8_lossy(&text).trim().to_string()
+}
+
+// ネームテーブル$2000の各行をテキストとして読む テストROMのフォントはタイル番号がASCIIコードと一致する
+fn read_screen_text(nes: &Nes) -> String {
+    let ppu = &nes.memory_map.ppu;
+    let mut lines = Vec::new();
+    for row in 0..30 {
+        let line: String = (0..32).map(|column| {
+            let tile = ppu.read_bus(0x2000 + row * 32 + column, &nes.memory_map.rom);
+            if (0x20..0x7F).contains(&tile) {tile as char} else {' '}
+        }).collect();
+        let line = line.trim();
+        if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+    lines.join("\n")
+}
+
+fn has_signature(nes: &mut Nes) -> bool {
+    SIGNATURE.iter().enumerate().all(|(i, byte)| nes.memory_map.get_from_address(SIGNATURE_ADDRESS + i as u32) == *byte)
+}
+
+fn run_rom(path: &Path) -> Outcome {
+    let buf = match fs::read(path) {
+        Ok(buf) => buf,
+        Err(e) => return Outcome::Error(e.to_string())
+    };
+    let cartridge = match rom::from_array(&buf) {
+        Ok(cartridge) => cartridge,
+        Err(e) => return Outcome::Error(e.to_string())
+    };
+    let mut nes = Nes::new(cartridge);
+    nes.reset();
+
+    let mut reset_at = None;
+    for frame in 0..TIMEOUT_FRAMES {
+        nes.run_frame();
+        if !has_signature(&mut nes) {
+            if frame >= SIGNATURE_TIMEOUT_FRAMES {
+                // $6000の結果出力に対応していないROMは画面に結果が出るのを待つ
+                let text = read_screen_text(&nes);
+                let lower = text.to_lowercase();
+                if lower.contains("failed") {
+                    return Outcome::Failed(None, text);
+                }
+                if lower.contains("passed") {
+                    return Outcome::Passed(text);
+                }
+            }
+            continue;
+        }
+        match nes.memory_map.get_from_address(STATUS_ADDRESS) {
+            STATUS_RUNNING => {},
+            STATUS_NEEDS_RESET => {
+                match reset_at {
+                    None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+                    Some(at) if frame >= at => {
+                        nes.reset();
+                        reset_at = None;
+                    },
+                    Some(_) => {}
+                }
+            },
+            0 => return Outcome::Passed(read_text(&mut nes)),
+            code => return Outcome::Failed(Some(code), read_text(&mut nes))
+        }
+    }
+    if has_signature(&mut nes) {
+        Outcome::Timeout(read_text(&mut nes))
+    } else {
+        Outcome::Timeout(format!("no $6000 status output or result on screen: {}", read_screen_text(&nes)))
+    }
+}
+
+// パニックした場合もその内容を失敗として報告し、残りのROMの実行を続ける
+fn run_rom_catching_panic(path: &Path) -> Outcome {
+    match panic::catch_unwind(|| run_rom(path)) {
+        Ok(outcome) => outcome,
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_default();
+            Outcome::Error(format!("panicked: {}", message))
+        }
+    }
+}
+
+fn find_roms(dir: &Path, roms: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nes")) {
+            roms.push(path);
+        }
+    }
+}
+
+#[test]
+fn test_roms() {
+    let dir = PathBuf::from(env::var("NES_TEST_ROMS").unwrap_or_else(|_| "./test_roms".to_string()));
+    let filter = env::var("NES_TEST_ROM_FILTER").unwrap_or_default();
+    let mut roms = Vec::new();
+    find_roms(&dir, &mut roms);
+    roms.retain(|path| path.to_string_lossy().contains(&filter));
+    roms.sort();
+    if roms.is_empty() {
+        println!("no test ROMs found in {}, skipped", dir.display());
+        return;
+    }
+
+    let mut failures = Vec::new();
+    for path in roms.iter() {
+        let name = path.strip_prefix(&dir).unwrap_or(path).display().to_string();
+        let outcome = run_rom_catching_panic(path);
+        // 結果のテキストは複数行なので1行にまとめて表示する
+        let line = match &outcome {
+            Outcome::Passed(text) => format!("PASS  {} {}", name, text.replace('\n', " / ")),
+            Outcome::Failed(Some(code), text) => format!("FAIL  {} (code {}) {}", name, code, text.replace('\n', " / ")),
+            Outcome::Failed(None, text) => format!("FAIL  {} (screen) {}", name, text.replace('\n', " / ")),
+            Outcome::Timeout(text) => format!("TIME  {} {}", name, text.replace('\n', " / ")),
+            Outcome::Error(e) => format!("ERROR {} {}", name, e)
+        };
+        println!("{}", line);
+        if let Outcome::Failed(..) | Outcome::Timeout(_) | Outcome::Error(_) = outcome {
+            failures.push(line);
+        }
+    }
+    assert!(failures.is_empty(), "{} of {} test ROMs failed:\n{}", failures.len(), roms.len(), failures.join("\n"));
+}